@@ -0,0 +1,364 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{
+    Bound,
+    Config,
+    Igdp,
+    error::{Error, Result},
+    notify::Announcements,
+    ssdp::{self, SearchConfig}
+};
+use log::{debug, trace};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs}, time::Duration};
+use tokio_reactor::Handle;
+use tokio_udp::UdpSocket;
+
+/// The default size of the buffer M-SEARCH responses are received into.
+const BUFFER_SIZE: usize = 65527;
+
+/// The range of `MX` values the UPnP spec allows.
+const MIN_MX: u8 = 1;
+const MAX_MX: u8 = 5;
+
+/// Configures and creates `Igdp` instances.
+///
+/// `Igdp::bind` is equivalent to `IgdpBuilder::new().bind(..)`.
+#[derive(Clone, Debug)]
+pub struct IgdpBuilder {
+    config: Config,
+    search: SearchConfig,
+    buffer_size: usize,
+    multicast_addr: Option<SocketAddr>,
+    multicast_ttl: Option<u32>,
+    multicast_interface: Option<Ipv4Addr>,
+    multicast_interface_v6: Option<u32>
+}
+
+impl Default for IgdpBuilder {
+    fn default() -> Self {
+        IgdpBuilder {
+            config: Config::default(),
+            search: SearchConfig::default(),
+            buffer_size: BUFFER_SIZE,
+            multicast_addr: None,
+            multicast_ttl: None,
+            multicast_interface: None,
+            multicast_interface_v6: None
+        }
+    }
+}
+
+impl IgdpBuilder {
+    /// Create a builder with the default settings.
+    pub fn new() -> Self {
+        IgdpBuilder::default()
+    }
+
+    /// Set how often M-SEARCH requests are sent. The default is 3.
+    ///
+    /// Requests are always sent at least once.
+    pub fn tries(mut self, tries: u32) -> Self {
+        self.search.tries = tries;
+        self
+    }
+
+    /// Set how long to wait for responses after each round of M-SEARCH
+    /// requests. The default is 1s.
+    pub fn wait(mut self, wait: Duration) -> Self {
+        self.search.wait = wait;
+        self
+    }
+
+    /// Set the `MX` value, i.e. the number of seconds devices may delay their
+    /// responses. The default is 1, the UPnP spec allows 1 to 5, so other
+    /// values are clamped to that range.
+    pub fn mx(mut self, mx: u8) -> Self {
+        self.search.mx = mx.clamp(MIN_MX, MAX_MX);
+        self
+    }
+
+    /// Set the address M-SEARCH requests are sent to.
+    ///
+    /// The default is 239.255.255.250:1900 if bound to an IPv4 address and
+    /// both [ff02::c]:1900 and [ff05::c]:1900 if bound to an IPv6 address.
+    pub fn multicast_addr(mut self, addr: SocketAddr) -> Self {
+        self.multicast_addr = Some(addr);
+        self
+    }
+
+    /// Set the size in bytes of the buffer M-SEARCH responses are received into.
+    ///
+    /// Longer responses are truncated. The default is 65527.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Set the time-to-live or, for IPv6, the hop limit of M-SEARCH requests.
+    /// The operating system's default (usually 1) is used unless set.
+    pub fn multicast_ttl(mut self, ttl: u32) -> Self {
+        self.multicast_ttl = Some(ttl);
+        self
+    }
+
+    /// Set the interface M-SEARCH requests are sent from by its IPv4 address.
+    /// The operating system chooses one unless set.
+    pub fn multicast_interface(mut self, addr: Ipv4Addr) -> Self {
+        self.multicast_interface = Some(addr);
+        self
+    }
+
+    /// Set the interface IPv6 M-SEARCH requests are sent from by its index.
+    ///
+    /// Unless set, this is the scope ID of the address we are bound to, if
+    /// any, and otherwise the operating system chooses one.
+    pub fn multicast_interface_v6(mut self, index: u32) -> Self {
+        self.multicast_interface_v6 = Some(index);
+        self
+    }
+
+    /// Set the search targets, most preferred first.
+    ///
+    /// The default targets are the `WANIPConnection` and `WANPPPConnection`
+    /// services and the `InternetGatewayDevice` device types. At least one
+    /// target is required, `bind` and `listen` fail with
+    /// `io::ErrorKind::InvalidInput` otherwise.
+    pub fn search_targets<I, S>(mut self, targets: I) -> Self
+    where
+        I: IntoIterator<Item=S>,
+        S: Into<String>
+    {
+        self.search.targets = targets.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the `CPFN.UPNP.ORG` friendly name of M-SEARCH requests.
+    /// The default is "upnp-igdp-crate".
+    pub fn friendly_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.search.friendly_name = name.into();
+        self
+    }
+
+    /// Enable or disable the validation of M-SEARCH responses. Enabled by default.
+    ///
+    /// When enabled, responses are ignored unless their `LOCATION` host is the
+    /// IP address of the sender and a private or link-local address, and
+    /// control URLs must point to the host of the device description.
+    pub fn validate_location(mut self, enabled: bool) -> Self {
+        self.search.validate_location = enabled;
+        self.config.validate_location = enabled;
+        self
+    }
+
+    /// See `Igdp::set_max_response_size`.
+    pub fn max_response_size(mut self, size: usize) -> Self {
+        self.config.max_response_size = size;
+        self
+    }
+
+    /// See `Igdp::set_connect_timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// See `Igdp::set_send_timeout`.
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.config.send_timeout = timeout;
+        self
+    }
+
+    /// See `Igdp::set_receive_timeout`.
+    pub fn receive_timeout(mut self, timeout: Duration) -> Self {
+        self.config.receive_timeout = timeout;
+        self
+    }
+
+    /// Create a new Igdp instance, binding the UDP port to the address provided.
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> Result<Igdp<()>> {
+        self.check_targets()?;
+        for a in addr.to_socket_addrs()? {
+            match self.socket(&a) {
+                Ok(socket) => {
+                    let local = socket.local_addr()?;
+                    trace!("new igdp instance bound to {}", local);
+                    return Ok(Igdp {
+                        local: local.ip(),
+                        config: self.config.clone(),
+                        bound: Some(Bound {
+                            socket,
+                            buffer: vec![0; self.buffer_size],
+                            search: SearchConfig {
+                                addrs: self.multicast_addrs(&a),
+                                .. self.search.clone()
+                            }
+                        }),
+                        state: ()
+                    })
+                }
+                Err(e) => debug!("failed to bind to {}: {}", a, e)
+            }
+        }
+        Err(Error::Bind)
+    }
+
+    /// Listen for SSDP `NOTIFY` announcements of gateways.
+    ///
+    /// Binds to the port of the multicast address, sharing it with other
+    /// listeners, and joins the multicast group on the configured interface.
+    /// The default group is 239.255.255.250:1900, see `listen_v6` for IPv6.
+    pub fn listen(&self) -> Result<Announcements> {
+        self.listen_to(&[self.multicast_addr.unwrap_or(ssdp::MULTICAST_V4)])
+    }
+
+    /// Like `listen` but joins both IPv6 SSDP groups, [ff02::c]:1900 and
+    /// [ff05::c]:1900, unless a multicast address is set.
+    ///
+    /// The groups are joined on the interface set with `multicast_interface_v6`
+    /// or, unless set, on one chosen by the operating system.
+    pub fn listen_v6(&self) -> Result<Announcements> {
+        match self.multicast_addr {
+            Some(addr) => self.listen_to(&[addr]),
+            None => self.listen_to(&ssdp::multicast_v6(self.multicast_interface_v6.unwrap_or(0)))
+        }
+    }
+
+    /// Listen for announcements to `groups`, which share address family and port.
+    fn listen_to(&self, groups: &[SocketAddr]) -> Result<Announcements> {
+        self.check_targets()?;
+        let (domain, unspecified) = match groups[0] {
+            SocketAddr::V4(_) => (Domain::ipv4(), IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            SocketAddr::V6(_) => (Domain::ipv6(), IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        };
+        let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        if groups[0].is_ipv6() {
+            socket.set_only_v6(true)?
+        }
+        socket.bind(&SockAddr::from(SocketAddr::new(unspecified, groups[0].port())))?;
+        for group in groups {
+            match group {
+                SocketAddr::V4(group) => {
+                    let interface = self.multicast_interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+                    socket.join_multicast_v4(group.ip(), &interface)?
+                }
+                SocketAddr::V6(group) => {
+                    let interface = self.multicast_interface_v6.unwrap_or_else(|| group.scope_id());
+                    socket.join_multicast_v6(group.ip(), interface)?
+                }
+            }
+            trace!("listening for announcements to {}", group)
+        }
+        let socket = UdpSocket::from_std(socket.into_udp_socket(), &Handle::default())?;
+        Ok(Announcements::new(socket, vec![0; self.buffer_size], &self.search))
+    }
+
+    fn check_targets(&self) -> io::Result<()> {
+        if self.search.targets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no search targets"))
+        }
+        Ok(())
+    }
+
+    /// The addresses to send M-SEARCH requests to when bound to `local`.
+    fn multicast_addrs(&self, local: &SocketAddr) -> Vec<SocketAddr> {
+        match (self.multicast_addr, local) {
+            (Some(addr), _) => vec![addr],
+            (None, SocketAddr::V4(_)) => vec![ssdp::MULTICAST_V4],
+            (None, SocketAddr::V6(local)) => {
+                ssdp::multicast_v6(self.multicast_interface_v6.unwrap_or_else(|| local.scope_id()))
+            }
+        }
+    }
+
+    fn socket(&self, addr: &SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::from(std::net::UdpSocket::bind(addr)?);
+        match addr {
+            SocketAddr::V4(_) => {
+                if let Some(ttl) = self.multicast_ttl {
+                    socket.set_multicast_ttl_v4(ttl)?
+                }
+                if let Some(interface) = self.multicast_interface {
+                    socket.set_multicast_if_v4(&interface)?
+                }
+            }
+            SocketAddr::V6(addr) => {
+                if let Some(hops) = self.multicast_ttl {
+                    socket.set_multicast_hops_v6(hops)?
+                }
+                let interface = self.multicast_interface_v6.unwrap_or_else(|| addr.scope_id());
+                if interface != 0 {
+                    socket.set_multicast_if_v6(interface)?
+                }
+            }
+        }
+        UdpSocket::from_std(socket.into_udp_socket(), &Handle::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bound(igdp: &Igdp<()>) -> &Bound {
+        igdp.bound.as_ref().unwrap()
+    }
+
+    #[test]
+    fn bind_with_settings() {
+        let igdp = IgdpBuilder::new()
+            .tries(1)
+            .wait(Duration::from_millis(100))
+            .mx(2)
+            .buffer_size(1024)
+            .multicast_ttl(2)
+            .multicast_interface(Ipv4Addr::LOCALHOST)
+            .search_targets(vec!["upnp:rootdevice"])
+            .friendly_name("test")
+            .validate_location(false)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        assert_eq!(1024, bound(&igdp).buffer.len());
+        assert_eq!(1, bound(&igdp).search.tries);
+        assert_eq!(vec!["upnp:rootdevice".to_string()], bound(&igdp).search.targets);
+        assert_eq!("test", bound(&igdp).search.friendly_name);
+        assert!(!bound(&igdp).search.validate_location && !igdp.config.validate_location);
+        assert_eq!(vec![ssdp::MULTICAST_V4], bound(&igdp).search.addrs)
+    }
+
+    #[test]
+    fn reject_empty_targets() {
+        match IgdpBuilder::new().search_targets(Vec::<String>::new()).bind("127.0.0.1:0") {
+            Err(Error::Io(e)) => assert_eq!(io::ErrorKind::InvalidInput, e.kind()),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn clamp_mx() {
+        assert_eq!(1, IgdpBuilder::new().mx(0).search.mx);
+        assert_eq!(5, IgdpBuilder::new().mx(120).search.mx);
+        assert_eq!(3, IgdpBuilder::new().mx(3).search.mx)
+    }
+
+    #[test]
+    fn bind_ipv6() {
+        // Without IPv6 support there is nothing to test.
+        let igdp = match IgdpBuilder::new().multicast_interface_v6(1).bind("[::1]:0") {
+            Ok(igdp) => igdp,
+            Err(_) => return
+        };
+        assert_eq!(ssdp::multicast_v6(1), bound(&igdp).search.addrs)
+    }
+}
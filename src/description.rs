@@ -0,0 +1,289 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{error::{Error, Result}, http, xml};
+use log::debug;
+use roxmltree::Document;
+use std::str;
+use url::Url;
+
+/// A UPnP device description, as published at the location of a gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceDescription {
+    /// The `URLBase` relative URLs are based on, if declared.
+    pub url_base: Option<Url>,
+    /// The root device, e.g. an `InternetGatewayDevice`.
+    pub device: Device
+}
+
+/// A device and its embedded devices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    /// The device type, e.g. `urn:schemas-upnp-org:device:WANDevice:1`.
+    pub device_type: String,
+    /// A short name for the end user.
+    pub friendly_name: String,
+    /// The manufacturer's name.
+    pub manufacturer: String,
+    /// The model name.
+    pub model_name: String,
+    /// The model number, if any.
+    pub model_number: Option<String>,
+    /// The unique device name, e.g. `uuid:...`.
+    pub udn: String,
+    /// The URL of the device's web interface, if any.
+    pub presentation_url: Option<Url>,
+    /// The services offered by this device.
+    pub services: Vec<Service>,
+    /// The devices embedded in this device.
+    pub devices: Vec<Device>
+}
+
+/// A service offered by a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Service {
+    /// The service type, e.g. `urn:schemas-upnp-org:service:WANIPConnection:1`.
+    pub service_type: String,
+    /// The service identifier, e.g. `urn:upnp-org:serviceId:WANIPConn1`.
+    pub service_id: String,
+    /// The URL of the service description.
+    pub scpd_url: Option<Url>,
+    /// The URL actions are sent to.
+    pub control_url: Option<Url>,
+    /// The URL for event subscriptions.
+    pub event_sub_url: Option<Url>
+}
+
+impl DeviceDescription {
+    /// Parse a device description document retrieved from `location`.
+    ///
+    /// Relative URLs are resolved against the `URLBase`, if the description
+    /// declares one, and otherwise against `location`. URLs which can not be
+    /// resolved are `None`.
+    pub fn parse(xml: &str, location: &Url) -> Result<Self> {
+        let document = Document::parse(xml)?;
+        let root = xml::Cursor::new(document.root()).get("root");
+        let url_base = text(&root, "URLBase")
+            .filter(|u| !u.is_empty())
+            .and_then(|u| {
+                Url::parse(&u).map_err(|e| debug!("ignoring invalid URLBase {}: {}", u, e)).ok()
+            });
+        let device = root.children("device").next().ok_or(Error::Description)?;
+        let device = parse_device(&device, url_base.as_ref().unwrap_or(location));
+        Ok(DeviceDescription { url_base, device })
+    }
+
+    /// All devices, starting with the root device, depth first.
+    pub fn devices(&self) -> impl Iterator<Item=&Device> {
+        let mut devices = Vec::new();
+        let mut stack = vec![&self.device];
+        while let Some(device) = stack.pop() {
+            devices.push(device);
+            stack.extend(device.devices.iter().rev())
+        }
+        devices.into_iter()
+    }
+
+    /// The services of all devices, in the order of `devices`.
+    pub fn services(&self) -> impl Iterator<Item=&Service> {
+        self.devices().flat_map(|d| d.services.iter())
+    }
+}
+
+/// Parse the device description in the body of an HTTP response from `location`.
+pub(crate) fn extract(response: &http::Response, location: &Url) -> Result<DeviceDescription> {
+    if Some(200) != response.head.code {
+        return Err(Error::StatusCode(response.head.code))
+    }
+    DeviceDescription::parse(str::from_utf8(&response.body)?, location)
+}
+
+fn text(cursor: &xml::Cursor, name: &str) -> Option<String> {
+    cursor.get(name).text().map(|t| t.trim().to_string())
+}
+
+/// Resolve the URL in element `name` against `base`.
+fn url(cursor: &xml::Cursor, name: &str, base: &Url) -> Option<Url> {
+    let url = text(cursor, name).filter(|u| !u.is_empty())?;
+    base.join(&url).map_err(|e| debug!("ignoring invalid {} {}: {}", name, url, e)).ok()
+}
+
+fn parse_device(device: &xml::Cursor, base: &Url) -> Device {
+    let services = device.get("serviceList").children("service")
+        .map(|s| Service {
+            service_type: text(&s, "serviceType").unwrap_or_default(),
+            service_id: text(&s, "serviceId").unwrap_or_default(),
+            scpd_url: url(&s, "SCPDURL", base),
+            control_url: url(&s, "controlURL", base),
+            event_sub_url: url(&s, "eventSubURL", base)
+        })
+        .collect();
+    let devices = device.get("deviceList").children("device")
+        .map(|d| parse_device(&d, base))
+        .collect();
+    Device {
+        device_type: text(device, "deviceType").unwrap_or_default(),
+        friendly_name: text(device, "friendlyName").unwrap_or_default(),
+        manufacturer: text(device, "manufacturer").unwrap_or_default(),
+        model_name: text(device, "modelName").unwrap_or_default(),
+        model_number: text(device, "modelNumber"),
+        udn: text(device, "UDN").unwrap_or_default(),
+        presentation_url: url(device, "presentationURL", base),
+        services,
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+        <root xmlns="urn:schemas-upnp-org:device-1-0">
+          <specVersion><major>1</major><minor>0</minor></specVersion>
+          <device>
+            <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+            <friendlyName>Router</friendlyName>
+            <manufacturer>ACME</manufacturer>
+            <modelName>Gateway</modelName>
+            <modelNumber>1.0</modelNumber>
+            <UDN>uuid:00000000-0000-0000-0000-000000000001</UDN>
+            <serviceList>
+              <service>
+                <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+                <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>
+                <SCPDURL>/L3F.xml</SCPDURL>
+                <controlURL>/ctl/L3F</controlURL>
+                <eventSubURL>/evt/L3F</eventSubURL>
+              </service>
+            </serviceList>
+            <deviceList>
+              <device>
+                <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+                <friendlyName>WAN Device</friendlyName>
+                <manufacturer>ACME</manufacturer>
+                <modelName>WAN</modelName>
+                <UDN>uuid:00000000-0000-0000-0000-000000000002</UDN>
+                <deviceList>
+                  <device>
+                    <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+                    <friendlyName>WAN Connection Device</friendlyName>
+                    <manufacturer>ACME</manufacturer>
+                    <modelName>WAN Connection</modelName>
+                    <UDN>uuid:00000000-0000-0000-0000-000000000003</UDN>
+                    <serviceList>
+                      <service>
+                        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                        <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+                        <SCPDURL>/WANIPCn.xml</SCPDURL>
+                        <controlURL>/ctl/IPConn</controlURL>
+                        <eventSubURL>/evt/IPConn</eventSubURL>
+                      </service>
+                    </serviceList>
+                  </device>
+                </deviceList>
+              </device>
+            </deviceList>
+            <presentationURL>http://192.168.1.1/</presentationURL>
+          </device>
+        </root>"#;
+
+    fn location() -> Url {
+        Url::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap()
+    }
+
+    fn resolved(url: &Option<Url>) -> Option<&str> {
+        url.as_ref().map(Url::as_str)
+    }
+
+    #[test]
+    fn parse_device_tree() {
+        let description = DeviceDescription::parse(DESCRIPTION, &location()).unwrap();
+        assert_eq!(None, description.url_base);
+        let root = &description.device;
+        assert_eq!("Router", root.friendly_name);
+        assert_eq!("ACME", root.manufacturer);
+        assert_eq!(Some("1.0"), root.model_number.as_deref());
+        assert_eq!(Some("http://192.168.1.1/"), resolved(&root.presentation_url));
+        let names: Vec<_> = description.devices().map(|d| d.friendly_name.as_str()).collect();
+        assert_eq!(vec!["Router", "WAN Device", "WAN Connection Device"], names);
+        let services: Vec<_> = description.services().map(|s| s.service_id.as_str()).collect();
+        assert_eq!(vec!["urn:upnp-org:serviceId:L3Forwarding1", "urn:upnp-org:serviceId:WANIPConn1"],
+            services);
+        let conn = &root.devices[0].devices[0].services[0];
+        assert_eq!(Some("http://192.168.1.1:5000/WANIPCn.xml"), resolved(&conn.scpd_url));
+        assert_eq!(Some("http://192.168.1.1:5000/ctl/IPConn"), resolved(&conn.control_url));
+        assert_eq!(Some("http://192.168.1.1:5000/evt/IPConn"), resolved(&conn.event_sub_url))
+    }
+
+    #[test]
+    fn parse_without_device() {
+        let xml = r#"<root xmlns="urn:schemas-upnp-org:device-1-0"></root>"#;
+        match DeviceDescription::parse(xml, &location()) {
+            Err(Error::Description) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    /// Descriptions of various routers, their location and the expected
+    /// presentation, SCPD, control and event URLs of the connection service.
+    const CORPUS: &[(&str, &str, [&str; 4])] = &[
+        (include_str!("../tests/descriptions/miniupnpd.xml"),
+         "http://192.168.1.1:5000/rootDesc.xml",
+         ["http://192.168.1.1/",
+          "http://192.168.1.1:5000/WANIPCn.xml",
+          "http://192.168.1.1:5000/ctl/IPConn",
+          "http://192.168.1.1:5000/evt/IPConn"]),
+        (include_str!("../tests/descriptions/fritzbox.xml"),
+         "http://192.168.178.1:49000/igddesc.xml",
+         ["http://fritz.box/",
+          "http://192.168.178.1:49000/igdconnSCPD.xml",
+          "http://192.168.178.1:49000/igdupnp/control/WANIPConn1",
+          "http://192.168.178.1:49000/igdupnp/control/WANIPConn1"]),
+        (include_str!("../tests/descriptions/urlbase.xml"),
+         "http://192.168.1.1:2869/upnp/IGD.xml",
+         ["http://192.168.1.1:49152/",
+          "http://192.168.1.1:49152/x_wanipconnection.xml",
+          "http://192.168.1.1:49152/upnp/control/WANIPConnection",
+          "http://192.168.1.1:49152/upnp/event/WANIPConnection"]),
+        (include_str!("../tests/descriptions/relative.xml"),
+         "http://192.168.0.1:1900/igd/desc.xml",
+         ["http://192.168.0.1:1900/index.html",
+          "http://192.168.0.1:1900/igd/wanpppcpppoa.xml",
+          "http://192.168.0.1:1900/igd/control/wanpppcpppoa",
+          "http://192.168.0.1:1900/igd/event/wanpppcpppoa"]),
+        (include_str!("../tests/descriptions/absolute.xml"),
+         "http://192.168.0.1:52869/picsdesc.xml",
+         ["http://192.168.0.1/",
+          "http://192.168.0.1:52869/picsdesc.xml?service=WANIPConn1",
+          "http://192.168.0.1:52869/upnp/control?service=WANIPConn1",
+          "http://192.168.0.1:52869/upnp/event?service=WANIPConn1"])
+    ];
+
+    #[test]
+    fn resolve_corpus_urls() {
+        for (xml, location, expected) in CORPUS {
+            let location = Url::parse(location).unwrap();
+            let description = DeviceDescription::parse(xml, &location).unwrap();
+            let conn = description.services()
+                .find(|s| s.service_type.contains("WANIPConnection")
+                    || s.service_type.contains("WANPPPConnection"))
+                .unwrap();
+            let urls = [
+                resolved(&description.device.presentation_url),
+                resolved(&conn.scpd_url),
+                resolved(&conn.control_url),
+                resolved(&conn.event_sub_url)
+            ];
+            let expected: Vec<_> = expected.iter().map(|u| Some(*u)).collect();
+            assert_eq!(&expected[..], &urls[..], "{}", location)
+        }
+    }
+}
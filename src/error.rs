@@ -8,6 +8,9 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
+#![allow(clippy::single_component_path_imports)]
+
+use crate::PortMappingEntry;
 use httparse;
 use roxmltree;
 use std::{fmt, io, str};
@@ -16,23 +19,39 @@ use url;
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
+#[allow(clippy::manual_non_exhaustive)]
 pub enum Error {
     /// Failed to bind UDP socket.
     Bind,
-    /// Action timed out.
-    Timeout,
+    /// The given step timed out.
+    Timeout(Step),
     /// Missing or invalid `Location` HTTP header.
     Location,
     /// Missing control URL in XML response.
     ControlUrl,
+    /// Missing root device in device description.
+    Description,
     /// Missing host and port information from URL.
     HostPort,
+    /// The URL scheme is not `http`.
+    Scheme(String),
     /// Unexpected HTTP status code.
     StatusCode(Option<u16>),
+    /// The gateway refused an action with a UPnP error code and description.
+    Upnp(ErrorCode, Option<String>),
+    /// The gateway refused a `WANIPv6FirewallControl` action with an error
+    /// code specific to that service and a description.
+    Firewall(FirewallErrorCode, Option<String>),
     /// General I/O error.
     Io(io::Error),
     /// Error parsing HTTP response.
     Http(httparse::Error),
+    /// The connection was closed before the HTTP response was complete.
+    Incomplete,
+    /// Invalid HTTP response body framing.
+    InvalidBody,
+    /// The HTTP response exceeds the given maximum size.
+    TooLarge(usize),
     /// Error parsing bytes as UTF-8.
     Utf8(str::Utf8Error),
     /// XML parsing error.
@@ -41,6 +60,14 @@ pub enum Error {
     Url(url::ParseError),
     /// Timer error.
     Timer,
+    /// A new port mapping as stored by the gateway differs from the request.
+    MappingMismatch(Box<PortMappingEntry>),
+    /// The gateway's service version does not support the named action.
+    Unsupported(&'static str),
+    /// The gateway refused a NAT-PMP request with the given result code.
+    NatPmp(NatPmpCode),
+    /// The server refused a PCP request with the given result code.
+    Pcp(PcpCode),
 
     #[doc(hidden)]
     __Nonexhaustive
@@ -50,23 +77,369 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Bind => f.write_str("error binding UDP socket"),
-            Error::Timeout => f.write_str("timeout"),
+            Error::Timeout(s) => write!(f, "timeout: {}", s),
             Error::Location => f.write_str("missing Location header"),
             Error::ControlUrl => f.write_str("missing control url"),
+            Error::Description => f.write_str("missing root device in device description"),
             Error::HostPort => f.write_str("missing host/port information in url"),
+            Error::Scheme(s) => write!(f, "unsupported url scheme: {}", s),
             Error::StatusCode(None) => f.write_str("missing http status code"),
             Error::StatusCode(Some(c)) => write!(f, "unexpected status code: {}", c),
+            Error::Upnp(c, None) => write!(f, "upnp error: {}", c),
+            Error::Upnp(c, Some(d)) => write!(f, "upnp error: {}: {}", c, d),
+            Error::Firewall(c, None) => write!(f, "upnp firewall error: {}", c),
+            Error::Firewall(c, Some(d)) => write!(f, "upnp firewall error: {}: {}", c, d),
             Error::Io(e) => write!(f, "i/o error: {}", e),
             Error::Http(e) => write!(f, "http parsing error: {}", e),
+            Error::Incomplete => f.write_str("incomplete http response"),
+            Error::InvalidBody => f.write_str("invalid http response body"),
+            Error::TooLarge(n) => write!(f, "http response exceeds {} bytes", n),
             Error::Utf8(e) => write!(f, "error parsing as utf-8: {}", e),
             Error::Xml(e) => write!(f, "xml parsing error: {}", e),
             Error::Url(e) => write!(f, "error parsing url: {}", e),
             Error::Timer => f.write_str("timer error"),
+            Error::MappingMismatch(e) => {
+                write!(f, "port mapping differs from request: {} port {} maps to {}:{}",
+                    e.protocol, e.external_port, e.internal_client, e.internal_port)
+            }
+            Error::Unsupported(a) => write!(f, "action not supported by service: {}", a),
+            Error::NatPmp(c) => write!(f, "nat-pmp error: {}", c),
+            Error::Pcp(c) => write!(f, "pcp error: {}", c),
             Error::__Nonexhaustive => f.write_str("__Nonexhausive")
         }
     }
 }
 
+/// The steps which may time out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Step {
+    /// No gateway responded to our SSDP M-SEARCH requests.
+    Discovery,
+    /// Establishing a TCP connection to the gateway.
+    Connect,
+    /// Sending an HTTP request to the gateway.
+    Send,
+    /// Receiving a response from the gateway.
+    Receive,
+    /// The overall deadline of an operation.
+    Deadline
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Discovery => f.write_str("discovery"),
+            Step::Connect => f.write_str("connect"),
+            Step::Send => f.write_str("send"),
+            Step::Receive => f.write_str("receive"),
+            Step::Deadline => f.write_str("deadline")
+        }
+    }
+}
+
+/// UPnP error codes returned by internet gateways.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// No action by that name at this service (401).
+    InvalidAction,
+    /// Not enough arguments, or arguments of the wrong type or value (402).
+    InvalidArgs,
+    /// The action failed for an unspecified reason (501).
+    ActionFailed,
+    /// The action requested is not permitted for this client (606).
+    ActionNotAuthorized,
+    /// The specified array index is out of bounds (713).
+    SpecifiedArrayIndexInvalid,
+    /// The specified value does not exist in the array (714).
+    NoSuchEntryInArray,
+    /// The source IP address cannot be wild-carded (715).
+    WildCardNotPermittedInSrcIp,
+    /// The external port cannot be wild-carded (716).
+    WildCardNotPermittedInExtPort,
+    /// The port mapping entry conflicts with one assigned previously to another client (718).
+    ConflictInMappingEntry,
+    /// Internal and external port values must be the same (724).
+    SamePortValuesRequired,
+    /// The gateway only supports permanent leases (725).
+    OnlyPermanentLeasesSupported,
+    /// The remote host must be a wildcard (726).
+    RemoteHostOnlySupportsWildcard,
+    /// The external port must be a wildcard (727).
+    ExternalPortOnlySupportsWildcard,
+    /// There are no free ports for mapping (728).
+    NoPortMapsAvailable,
+    /// No port mapping was found in the specified range (730).
+    PortMappingNotFound,
+    /// Any other error code.
+    Other(u16)
+}
+
+impl ErrorCode {
+    /// The numeric error code.
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::InvalidAction => 401,
+            ErrorCode::InvalidArgs => 402,
+            ErrorCode::ActionFailed => 501,
+            ErrorCode::ActionNotAuthorized => 606,
+            ErrorCode::SpecifiedArrayIndexInvalid => 713,
+            ErrorCode::NoSuchEntryInArray => 714,
+            ErrorCode::WildCardNotPermittedInSrcIp => 715,
+            ErrorCode::WildCardNotPermittedInExtPort => 716,
+            ErrorCode::ConflictInMappingEntry => 718,
+            ErrorCode::SamePortValuesRequired => 724,
+            ErrorCode::OnlyPermanentLeasesSupported => 725,
+            ErrorCode::RemoteHostOnlySupportsWildcard => 726,
+            ErrorCode::ExternalPortOnlySupportsWildcard => 727,
+            ErrorCode::NoPortMapsAvailable => 728,
+            ErrorCode::PortMappingNotFound => 730,
+            ErrorCode::Other(c) => c
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            401 => ErrorCode::InvalidAction,
+            402 => ErrorCode::InvalidArgs,
+            501 => ErrorCode::ActionFailed,
+            606 => ErrorCode::ActionNotAuthorized,
+            713 => ErrorCode::SpecifiedArrayIndexInvalid,
+            714 => ErrorCode::NoSuchEntryInArray,
+            715 => ErrorCode::WildCardNotPermittedInSrcIp,
+            716 => ErrorCode::WildCardNotPermittedInExtPort,
+            718 => ErrorCode::ConflictInMappingEntry,
+            724 => ErrorCode::SamePortValuesRequired,
+            725 => ErrorCode::OnlyPermanentLeasesSupported,
+            726 => ErrorCode::RemoteHostOnlySupportsWildcard,
+            727 => ErrorCode::ExternalPortOnlySupportsWildcard,
+            728 => ErrorCode::NoPortMapsAvailable,
+            730 => ErrorCode::PortMappingNotFound,
+            c => ErrorCode::Other(c)
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::Other(c) => write!(f, "{}", c),
+            c => write!(f, "{:?} ({})", c, c.code())
+        }
+    }
+}
+
+/// UPnP error codes specific to the `WANIPv6FirewallControl` service.
+///
+/// These overlap with codes `WANIPConnection` uses for other errors, so they
+/// are only decoded for firewall actions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FirewallErrorCode {
+    /// The gateway cannot create more pinholes (701).
+    PinholeSpaceExhausted,
+    /// The firewall is disabled (702).
+    FirewallDisabled,
+    /// Inbound pinholes are not allowed by the firewall (703).
+    InboundPinholeNotAllowed,
+    /// No pinhole with the given unique ID exists (704).
+    NoSuchEntry,
+    /// The protocol is not supported by the firewall (705).
+    ProtocolNotSupported,
+    /// The internal port cannot be wild-carded (706).
+    InternalPortWildcardingNotAllowed,
+    /// The protocol cannot be wild-carded (707).
+    ProtocolWildcardingNotAllowed,
+    /// The pinhole's remote host cannot be wild-carded (708).
+    WildCardNotPermittedInSrcIp,
+    /// No traffic has passed through the pinhole since it was created (709).
+    NoTrafficReceived,
+    /// Any other error code.
+    Other(u16)
+}
+
+impl FirewallErrorCode {
+    /// The numeric error code.
+    pub fn code(self) -> u16 {
+        match self {
+            FirewallErrorCode::PinholeSpaceExhausted => 701,
+            FirewallErrorCode::FirewallDisabled => 702,
+            FirewallErrorCode::InboundPinholeNotAllowed => 703,
+            FirewallErrorCode::NoSuchEntry => 704,
+            FirewallErrorCode::ProtocolNotSupported => 705,
+            FirewallErrorCode::InternalPortWildcardingNotAllowed => 706,
+            FirewallErrorCode::ProtocolWildcardingNotAllowed => 707,
+            FirewallErrorCode::WildCardNotPermittedInSrcIp => 708,
+            FirewallErrorCode::NoTrafficReceived => 709,
+            FirewallErrorCode::Other(c) => c
+        }
+    }
+}
+
+impl From<u16> for FirewallErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            701 => FirewallErrorCode::PinholeSpaceExhausted,
+            702 => FirewallErrorCode::FirewallDisabled,
+            703 => FirewallErrorCode::InboundPinholeNotAllowed,
+            704 => FirewallErrorCode::NoSuchEntry,
+            705 => FirewallErrorCode::ProtocolNotSupported,
+            706 => FirewallErrorCode::InternalPortWildcardingNotAllowed,
+            707 => FirewallErrorCode::ProtocolWildcardingNotAllowed,
+            708 => FirewallErrorCode::WildCardNotPermittedInSrcIp,
+            709 => FirewallErrorCode::NoTrafficReceived,
+            c => FirewallErrorCode::Other(c)
+        }
+    }
+}
+
+impl fmt::Display for FirewallErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FirewallErrorCode::Other(c) => write!(f, "{}", c),
+            c => write!(f, "{:?} ({})", c, c.code())
+        }
+    }
+}
+
+/// NAT-PMP result codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NatPmpCode {
+    /// The gateway does not support our version of NAT-PMP (1).
+    UnsupportedVersion,
+    /// The gateway supports mapping, but it has been disabled (2).
+    NotAuthorized,
+    /// The gateway lacks an external address, e.g. without a DHCP lease (3).
+    NetworkFailure,
+    /// The gateway cannot create more mappings (4).
+    OutOfResources,
+    /// The gateway does not support the request (5).
+    UnsupportedOpcode,
+    /// Any other result code.
+    Other(u16)
+}
+
+impl NatPmpCode {
+    /// The numeric result code.
+    pub fn code(self) -> u16 {
+        match self {
+            NatPmpCode::UnsupportedVersion => 1,
+            NatPmpCode::NotAuthorized => 2,
+            NatPmpCode::NetworkFailure => 3,
+            NatPmpCode::OutOfResources => 4,
+            NatPmpCode::UnsupportedOpcode => 5,
+            NatPmpCode::Other(c) => c
+        }
+    }
+}
+
+impl From<u16> for NatPmpCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => NatPmpCode::UnsupportedVersion,
+            2 => NatPmpCode::NotAuthorized,
+            3 => NatPmpCode::NetworkFailure,
+            4 => NatPmpCode::OutOfResources,
+            5 => NatPmpCode::UnsupportedOpcode,
+            c => NatPmpCode::Other(c)
+        }
+    }
+}
+
+impl fmt::Display for NatPmpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NatPmpCode::Other(c) => write!(f, "{}", c),
+            c => write!(f, "{:?} ({})", c, c.code())
+        }
+    }
+}
+
+/// PCP result codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PcpCode {
+    /// The server does not support our version of PCP (1).
+    UnsupportedVersion,
+    /// The request is not authorized, e.g. because mapping is disabled (2).
+    NotAuthorized,
+    /// The request could not be parsed (3).
+    MalformedRequest,
+    /// The server does not support the opcode (4).
+    UnsupportedOpcode,
+    /// The server does not support a mandatory option (5).
+    UnsupportedOption,
+    /// An option is malformed (6).
+    MalformedOption,
+    /// The server or the device it controls has a network failure (7).
+    NetworkFailure,
+    /// The server cannot create more mappings right now (8).
+    NoResources,
+    /// The server does not support the protocol (9).
+    UnsupportedProtocol,
+    /// We exceeded our quota of mappings (10).
+    UserExceededQuota,
+    /// The suggested external port or address cannot be provided (11).
+    CannotProvideExternal,
+    /// Our address differs from the one the server received the request from (12).
+    AddressMismatch,
+    /// The server cannot create more filters (13).
+    ExcessiveRemotePeers,
+    /// Any other result code.
+    Other(u8)
+}
+
+impl PcpCode {
+    /// The numeric result code.
+    pub fn code(self) -> u8 {
+        match self {
+            PcpCode::UnsupportedVersion => 1,
+            PcpCode::NotAuthorized => 2,
+            PcpCode::MalformedRequest => 3,
+            PcpCode::UnsupportedOpcode => 4,
+            PcpCode::UnsupportedOption => 5,
+            PcpCode::MalformedOption => 6,
+            PcpCode::NetworkFailure => 7,
+            PcpCode::NoResources => 8,
+            PcpCode::UnsupportedProtocol => 9,
+            PcpCode::UserExceededQuota => 10,
+            PcpCode::CannotProvideExternal => 11,
+            PcpCode::AddressMismatch => 12,
+            PcpCode::ExcessiveRemotePeers => 13,
+            PcpCode::Other(c) => c
+        }
+    }
+}
+
+impl From<u8> for PcpCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => PcpCode::UnsupportedVersion,
+            2 => PcpCode::NotAuthorized,
+            3 => PcpCode::MalformedRequest,
+            4 => PcpCode::UnsupportedOpcode,
+            5 => PcpCode::UnsupportedOption,
+            6 => PcpCode::MalformedOption,
+            7 => PcpCode::NetworkFailure,
+            8 => PcpCode::NoResources,
+            9 => PcpCode::UnsupportedProtocol,
+            10 => PcpCode::UserExceededQuota,
+            11 => PcpCode::CannotProvideExternal,
+            12 => PcpCode::AddressMismatch,
+            13 => PcpCode::ExcessiveRemotePeers,
+            c => PcpCode::Other(c)
+        }
+    }
+}
+
+impl fmt::Display for PcpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PcpCode::Other(c) => write!(f, "{}", c),
+            c => write!(f, "{:?} ({})", c, c.code())
+        }
+    }
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
@@ -0,0 +1,357 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{
+    Config,
+    Discovery,
+    Igdp,
+    Protocol,
+    error::{Error, ErrorCode, FirewallErrorCode, Result},
+    extract_soap_response,
+    fetch_description,
+    http,
+    internal_client,
+    util
+};
+use futures::{future::{self, Either}, prelude::*};
+use log::trace;
+use std::{fmt, net::{IpAddr, Ipv4Addr, SocketAddr}, str::FromStr, time::Duration};
+use unicase::Ascii;
+use url::Url;
+
+/// `Igdp` state after the `WANIPv6FirewallControl` service has been found.
+///
+/// Instead of port mappings, this service opens pinholes in the gateway's
+/// firewall, letting inbound IPv6 traffic reach an internal client.
+#[derive(Clone, Debug)]
+pub struct Firewall {
+    url: Url,
+    addr: SocketAddr,
+    /// The service type, e.g. `urn:schemas-upnp-org:service:WANIPv6FirewallControl:1`.
+    service: String
+}
+
+/// The unique ID the gateway assigned to a pinhole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UniqueId(u16);
+
+impl UniqueId {
+    /// Refer to a pinhole by an ID obtained earlier, e.g. by another process.
+    pub fn new(id: u16) -> Self {
+        UniqueId(id)
+    }
+
+    /// The numeric value of the ID.
+    pub fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl fmt::Display for UniqueId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The result of `GetFirewallStatus`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirewallStatus {
+    /// Whether the firewall is enabled. If not, all inbound traffic passes.
+    pub enabled: bool,
+    /// Whether pinholes may be created.
+    pub inbound_pinhole_allowed: bool
+}
+
+/// The inbound traffic a pinhole lets through, `None` matches any value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pinhole {
+    /// The remote host traffic originates from.
+    pub remote_host: Option<IpAddr>,
+    /// The remote port traffic originates from.
+    pub remote_port: Option<u16>,
+    /// The internal client traffic is destined to.
+    pub internal_client: IpAddr,
+    /// The internal port traffic is destined to.
+    pub internal_port: Option<u16>,
+    /// The transport protocol of the traffic.
+    pub protocol: Option<Protocol>
+}
+
+impl Pinhole {
+    /// A pinhole for traffic from any remote host and port to the given
+    /// protocol and port of an internal client.
+    pub fn new(internal_client: IpAddr, protocol: Protocol, internal_port: u16) -> Self {
+        Pinhole {
+            remote_host: None,
+            remote_port: None,
+            internal_client,
+            internal_port: Some(internal_port),
+            protocol: Some(protocol)
+        }
+    }
+
+    fn arguments(&self) -> Vec<(&'static str, String)> {
+        let protocol = self.protocol.map(|p| u16::from(p.number())).unwrap_or(65535);
+        vec![
+            ("RemoteHost", self.remote_host.map(|a| a.to_string()).unwrap_or_default()),
+            ("RemotePort", self.remote_port.unwrap_or(0).to_string()),
+            ("InternalClient", self.internal_client.to_string()),
+            ("InternalPort", self.internal_port.unwrap_or(0).to_string()),
+            ("Protocol", protocol.to_string())
+        ]
+    }
+}
+
+impl Igdp<Discovery> {
+    /// Find the control URL of the gateway's `WANIPv6FirewallControl` service.
+    ///
+    /// Fails with `Error::ControlUrl` if it points to another host than the
+    /// device description, unless disabled with `set_validate_location`.
+    pub fn firewall(self) -> impl Future<Item=Igdp<Firewall>, Error=Error> {
+        let (local, config) = (self.local, self.config);
+        let (scope_id, gateway) = (self.state.scope_id(), self.state.addr);
+        let validate = config.validate_location;
+        fetch_description(&self.state, &config)
+            .and_then(|description| {
+                let service_type = Ascii::new(util::WAN_IPV6_FIREWALL_CONTROL_1);
+                description.services()
+                    .filter(|s| Ascii::new(s.service_type.as_str()) == service_type)
+                    .find_map(|s| Some((s.control_url.clone()?, s.service_type.clone())))
+                    .ok_or(Error::ControlUrl)
+            })
+            .and_then(move |(url, service)| {
+                util::resolve(&url, scope_id).and_then(move |addr| {
+                    if validate {
+                        util::check_control_addr(&url, addr, gateway)?
+                    }
+                    Ok(Firewall { url, addr, service })
+                })
+            })
+            .map(move |state| {
+                trace!("extracted control url {} of {}", state.url, state.service);
+                Igdp { local, config, bound: None, state }
+            })
+    }
+}
+
+impl Clone for Igdp<Firewall> {
+    fn clone(&self) -> Self {
+        let state = self.state.clone();
+        Igdp { local: self.local, config: self.config.clone(), bound: None, state }
+    }
+}
+
+impl Igdp<Firewall> {
+    /// Create an Igdp instance for a known control URL of a
+    /// `WANIPv6FirewallControl:1` service.
+    ///
+    /// Host names and zone IDs are supported as in `Igdp::from_location`.
+    pub fn from_firewall_control<U: AsRef<str>>(url: U) -> impl Future<Item=Self, Error=Error> {
+        let (url, scope_id) = match util::parse_url(url.as_ref()) {
+            Ok(x) => x,
+            Err(e) => return Either::A(future::err(e))
+        };
+        let service = util::WAN_IPV6_FIREWALL_CONTROL_1.to_string();
+        Either::B(util::resolve(&url, scope_id).map(move |addr| {
+            let local = internal_client(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr);
+            let state = Firewall { url, addr, service };
+            Igdp { local, config: Config::default(), bound: None, state }
+        }))
+    }
+
+    /// Check whether the firewall is enabled and allows pinholes.
+    pub fn firewall_status(self) -> impl Future<Item=(Self, FirewallStatus), Error=Error> {
+        self.call("GetFirewallStatus", &[], |response| {
+            let flag = |name| extract_value(response, "GetFirewallStatus", name).map(|f: Flag| f.0);
+            Ok(FirewallStatus {
+                enabled: flag("FirewallEnabled")?,
+                inbound_pinhole_allowed: flag("InboundPinholeAllowed")?
+            })
+        })
+    }
+
+    /// Get how long the firewall keeps outbound connections matching
+    /// `pinhole` open without traffic.
+    pub fn outbound_pinhole_timeout(self, pinhole: &Pinhole)
+        -> impl Future<Item=(Self, Duration), Error=Error>
+    {
+        self.call("GetOutboundPinholeTimeout", &pinhole.arguments(), |response| {
+            let action = "GetOutboundPinholeTimeout";
+            extract_value(response, action, "OutboundPinholeTimeout").map(Duration::from_secs)
+        })
+    }
+
+    /// Open a pinhole for the given lease time, which the gateway limits to
+    /// between 1 and 86400 seconds.
+    pub fn add_pinhole(self, pinhole: &Pinhole, lease: Duration)
+        -> impl Future<Item=(Self, UniqueId), Error=Error>
+    {
+        let mut args = pinhole.arguments();
+        args.push(("LeaseTime", lease.as_secs().to_string()));
+        self.call("AddPinhole", &args, |response| {
+            let id = extract_value(response, "AddPinhole", "UniqueID").map(UniqueId)?;
+            trace!("added pinhole {}", id);
+            Ok(id)
+        })
+    }
+
+    /// Extend the lease of a pinhole.
+    pub fn update_pinhole(self, id: UniqueId, lease: Duration)
+        -> impl Future<Item=Self, Error=Error>
+    {
+        let args = [("UniqueID", id.to_string()), ("NewLeaseTime", lease.as_secs().to_string())];
+        self.call("UpdatePinhole", &args, |response| {
+            extract_soap_response(response, "UpdatePinhole", |_| ())
+        })
+        .map(|(igdp, ())| igdp)
+    }
+
+    /// Close a pinhole.
+    pub fn delete_pinhole(self, id: UniqueId) -> impl Future<Item=Self, Error=Error> {
+        self.call("DeletePinhole", &[("UniqueID", id.to_string())], move |response| {
+            extract_soap_response(response, "DeletePinhole", |_| ())?;
+            trace!("deleted pinhole {}", id);
+            Ok(())
+        })
+        .map(|(igdp, ())| igdp)
+    }
+
+    /// Get the number of packets which have gone through a pinhole.
+    pub fn pinhole_packets(self, id: UniqueId) -> impl Future<Item=(Self, u32), Error=Error> {
+        self.call("GetPinholePackets", &[("UniqueID", id.to_string())], |response| {
+            extract_value(response, "GetPinholePackets", "PinholePackets")
+        })
+    }
+
+    /// Check whether traffic has gone through a pinhole.
+    ///
+    /// A gateway which has not seen any traffic yet may report this as an
+    /// error, which is turned into `false`.
+    pub fn check_pinhole_working(self, id: UniqueId)
+        -> impl Future<Item=(Self, bool), Error=Error>
+    {
+        self.call("CheckPinholeWorking", &[("UniqueID", id.to_string())], extract_working)
+    }
+
+    /// Invoke `action` and extract its result with `f`.
+    fn call<F, T>(self, action: &str, args: &[(&str, String)], f: F)
+        -> impl Future<Item=(Self, T), Error=Error>
+    where
+        F: FnOnce(&http::Response) -> Result<T>
+    {
+        let req = util::format_soap_request(&self.state.url, &self.state.service, action, args);
+        trace!("connecting to {}", self.state.addr);
+        util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| Ok((self, f(&response).map_err(decode_fault)?)))
+    }
+}
+
+/// Turn `Error::Upnp` with an error code specific to `WANIPv6FirewallControl`
+/// into `Error::Firewall`.
+fn decode_fault(e: Error) -> Error {
+    match e {
+        Error::Upnp(ErrorCode::Other(c @ 701 ..= 709), d) => Error::Firewall(c.into(), d),
+        e => e
+    }
+}
+
+/// A boolean as encoded in SOAP responses.
+struct Flag(bool);
+
+impl FromStr for Flag {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "1" | "true" | "yes" => Ok(Flag(true)),
+            "0" | "false" | "no" => Ok(Flag(false)),
+            _ => Err(())
+        }
+    }
+}
+
+/// Extract the output argument `name` of a response to `action`.
+fn extract_value<T: FromStr>(response: &http::Response, action: &'static str, name: &str)
+    -> Result<T>
+{
+    let value = extract_soap_response(response, action, |r| {
+        r.get(name).text().and_then(|t| t.trim().parse().ok())
+    })
+    .map_err(decode_fault)?;
+    value.ok_or(Error::Unsupported(action))
+}
+
+fn extract_working(response: &http::Response) -> Result<bool> {
+    match extract_value::<Flag>(response, "CheckPinholeWorking", "IsWorking") {
+        Ok(flag) => Ok(flag.0),
+        Err(Error::Firewall(FirewallErrorCode::NoTrafficReceived, _)) => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(bytes: &[u8]) -> http::Response {
+        http::read_response(bytes, 64 * 1024).wait().unwrap()
+    }
+
+    #[test]
+    fn pinhole_arguments() {
+        let mut pinhole = Pinhole::new("2001:db8::10".parse().unwrap(), Protocol::Udp, 30303);
+        assert_eq!(vec![
+            ("RemoteHost", String::new()),
+            ("RemotePort", "0".to_string()),
+            ("InternalClient", "2001:db8::10".to_string()),
+            ("InternalPort", "30303".to_string()),
+            ("Protocol", "17".to_string())
+        ], pinhole.arguments());
+        pinhole.protocol = None;
+        assert_eq!(("Protocol", "65535".to_string()), pinhole.arguments()[4])
+    }
+
+    #[test]
+    fn extract_pinhole_results() {
+        let added = b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n\
+            <?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+              <s:Body>\
+                <u:AddPinholeResponse \
+                    xmlns:u=\"urn:schemas-upnp-org:service:WANIPv6FirewallControl:1\">\
+                  <UniqueID>42</UniqueID>\
+                </u:AddPinholeResponse>\
+              </s:Body>\
+            </s:Envelope>";
+        let id = extract_value(&response(&added[..]), "AddPinhole", "UniqueID").map(UniqueId);
+        assert_eq!(UniqueId::new(42), id.unwrap());
+
+        let fault = b"HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/xml\r\n\r\n\
+            <?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+              <s:Body><s:Fault>\
+                <faultcode>s:Client</faultcode>\
+                <faultstring>UPnPError</faultstring>\
+                <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+                  <errorCode>709</errorCode>\
+                  <errorDescription>NoTrafficReceived</errorDescription>\
+                </UPnPError></detail>\
+              </s:Fault></s:Body>\
+            </s:Envelope>";
+        assert!(!extract_working(&response(&fault[..])).unwrap());
+        let fault = String::from_utf8(fault.to_vec()).unwrap().replace("709", "704");
+        match extract_working(&response(fault.as_bytes())) {
+            Err(Error::Firewall(FirewallErrorCode::NoSuchEntry, _)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+}
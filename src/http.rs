@@ -0,0 +1,375 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::error::{Error, Result};
+use futures::{prelude::*, try_ready};
+use log::trace;
+use std::str;
+use tokio_io::AsyncRead;
+use unicase::Ascii;
+
+/// The number of headers we initially make room for.
+const HEADERS: usize = 32;
+
+/// Upper bound of the number of headers we accept.
+const MAX_HEADERS: usize = 1024;
+
+/// The status line and headers of an HTTP response.
+#[derive(Debug)]
+pub(crate) struct Head {
+    pub(crate) code: Option<u16>,
+    pub(crate) headers: Vec<(String, String)>
+}
+
+impl Head {
+    /// The value of the first header with the given name.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| Ascii::new(n.as_str()) == Ascii::new(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A complete HTTP response.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) head: Head,
+    pub(crate) body: Vec<u8>
+}
+
+/// Parse the status line and headers of an HTTP response.
+///
+/// Returns `None` if `bytes` does not contain the complete head yet and
+/// otherwise the head and the offset of the body.
+pub(crate) fn parse_head(bytes: &[u8]) -> Result<Option<(Head, usize)>> {
+    let mut n = HEADERS;
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; n];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(bytes) {
+            Ok(httparse::Status::Complete(offset)) => {
+                let headers = owned_headers(response.headers);
+                return Ok(Some((Head { code: response.code, headers }, offset)))
+            }
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(httparse::Error::TooManyHeaders) if n < MAX_HEADERS => n *= 2,
+            Err(e) => return Err(e.into())
+        }
+    }
+}
+
+/// Parse the request line and headers of an HTTP request, e.g. an SSDP `NOTIFY`.
+///
+/// Returns `None` if `bytes` does not contain the complete head yet and
+/// otherwise the request method and head, which has no status code.
+pub(crate) fn parse_request_head(bytes: &[u8]) -> Result<Option<(String, Head)>> {
+    let mut n = HEADERS;
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; n];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(bytes) {
+            Ok(httparse::Status::Complete(_)) => {
+                let method = request.method.unwrap_or_default().to_string();
+                let headers = owned_headers(request.headers);
+                return Ok(Some((method, Head { code: None, headers })))
+            }
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(httparse::Error::TooManyHeaders) if n < MAX_HEADERS => n *= 2,
+            Err(e) => return Err(e.into())
+        }
+    }
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|h| {
+            let value = String::from_utf8_lossy(h.value).trim().to_string();
+            (h.name.to_string(), value)
+        })
+        .collect()
+}
+
+/// How the end of a response body is determined.
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    Chunked,
+    Length(usize),
+    Close
+}
+
+impl Framing {
+    fn of(head: &Head) -> Result<Self> {
+        if let Some(te) = head.header("Transfer-Encoding") {
+            if te.split(',').any(|t| Ascii::new(t.trim()) == "chunked") {
+                return Ok(Framing::Chunked)
+            }
+        }
+        if let Some(len) = head.header("Content-Length") {
+            return len.parse().map(Framing::Length).map_err(|_| Error::InvalidBody)
+        }
+        Ok(Framing::Close)
+    }
+}
+
+/// Decode the chunks at the start of `bytes` into `body`.
+///
+/// Returns the number of bytes taken up by the decoded chunks and whether
+/// the body is complete. Incomplete chunks are left for the next call.
+fn decode_chunks(bytes: &[u8], body: &mut Vec<u8>) -> Result<(usize, bool)> {
+    let mut consumed = 0;
+    loop {
+        let (offset, size) = match httparse::parse_chunk_size(&bytes[consumed ..]) {
+            Ok(httparse::Status::Complete(x)) => x,
+            Ok(httparse::Status::Partial) => return Ok((consumed, false)),
+            Err(_) => return Err(Error::InvalidBody)
+        };
+        let mut rest = &bytes[consumed + offset ..];
+        if size == 0 {
+            // Skip the trailer section up to and including the final empty line.
+            loop {
+                match rest.iter().position(|b| *b == b'\n') {
+                    Some(0) => return Ok((bytes.len() - rest.len() + 1, true)),
+                    Some(1) if rest[0] == b'\r' => return Ok((bytes.len() - rest.len() + 2, true)),
+                    Some(i) => rest = &rest[i + 1 ..],
+                    None => return Ok((consumed, false))
+                }
+            }
+        }
+        if (rest.len() as u64) < size + 2 {
+            return Ok((consumed, false))
+        }
+        let size = size as usize;
+        body.extend_from_slice(&rest[.. size]);
+        if &rest[size .. size + 2] != b"\r\n" {
+            return Err(Error::InvalidBody)
+        }
+        consumed += offset + size + 2
+    }
+}
+
+/// Find the end of the head, i.e. the offset after the first empty line,
+/// looking at line endings from `from` onwards.
+fn find_head_end(bytes: &[u8], from: usize) -> Option<usize> {
+    (std::cmp::max(from, 1) .. bytes.len())
+        .find(|&i| {
+            bytes[i] == b'\n' && (bytes[i - 1] == b'\n'
+                || (i > 1 && bytes[i - 1] == b'\r' && bytes[i - 2] == b'\n'))
+        })
+        .map(|i| i + 1)
+}
+
+/// Read a single HTTP response of at most `max_size` bytes.
+///
+/// Interim `1xx` responses, e.g. `100 Continue`, are skipped.
+pub(crate) fn read_response<R: AsyncRead>(reader: R, max_size: usize) -> ReadResponse<R> {
+    ReadResponse {
+        reader,
+        buffer: Vec::new(),
+        max_size,
+        scanned: 0,
+        head: None,
+        offset: 0,
+        chunks: Vec::new()
+    }
+}
+
+/// Future returned by `read_response`.
+#[derive(Debug)]
+pub(crate) struct ReadResponse<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    max_size: usize,
+    /// How much of `buffer` has been searched for the end of the head.
+    scanned: usize,
+    /// The head of the final response and the framing of its body.
+    head: Option<(Head, Framing)>,
+    /// The offset of the body or, if chunked, of the next undecoded chunk.
+    offset: usize,
+    /// The decoded chunks of a chunked body.
+    chunks: Vec<u8>
+}
+
+impl<R> ReadResponse<R> {
+    /// Process the bytes received so far.
+    ///
+    /// Returns `None` if more data is needed, unless `eof` is set, in which
+    /// case an incomplete response is an error.
+    fn advance(&mut self, eof: bool) -> Result<Option<Response>> {
+        while self.head.is_none() {
+            // Look at the last line ending before the new bytes again, in
+            // case the empty line starts there.
+            let end = match find_head_end(&self.buffer, self.scanned.saturating_sub(3)) {
+                Some(end) => end,
+                None if eof => return Err(Error::Incomplete),
+                None => {
+                    self.scanned = self.buffer.len();
+                    return Ok(None)
+                }
+            };
+            let (head, _) = parse_head(&self.buffer[.. end])?.ok_or(Error::Incomplete)?;
+            if let Some(100 ..= 199) = head.code {
+                trace!("skipping interim response {:?}", head.code);
+                self.buffer.drain(.. end);
+                self.scanned = 0;
+                continue
+            }
+            let framing = Framing::of(&head)?;
+            self.head = Some((head, framing));
+            self.offset = end
+        }
+        let framing = self.head.as_ref().map(|(_, framing)| *framing).expect("head is set");
+        let body = &self.buffer[self.offset ..];
+        let body = match framing {
+            Framing::Chunked => {
+                let (consumed, done) = decode_chunks(body, &mut self.chunks)?;
+                self.offset += consumed;
+                if done { Some(std::mem::take(&mut self.chunks)) } else { None }
+            }
+            Framing::Length(n) if body.len() >= n => Some(body[.. n].to_vec()),
+            Framing::Length(_) => None,
+            Framing::Close if eof => Some(body.to_vec()),
+            Framing::Close => None
+        };
+        match body {
+            Some(body) => {
+                let (head, _) = self.head.take().expect("head is set");
+                Ok(Some(Response { head, body }))
+            }
+            None if eof => Err(Error::Incomplete),
+            None => Ok(None)
+        }
+    }
+}
+
+impl<R: AsyncRead> Future for ReadResponse<R> {
+    type Item = Response;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            let n = try_ready!(self.reader.poll_read(&mut chunk));
+            if n == 0 {
+                let response = self.advance(true)?;
+                return Ok(Async::Ready(response.ok_or(Error::Incomplete)?))
+            }
+            if self.buffer.len() + n > self.max_size {
+                return Err(Error::TooLarge(self.max_size))
+            }
+            self.buffer.extend_from_slice(&chunk[.. n]);
+            if let Some(response) = self.advance(false)? {
+                return Ok(Async::Ready(response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Result<Response> {
+        read_response(bytes, 1024).wait()
+    }
+
+    #[test]
+    fn content_length() {
+        let r = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloTRAILING GARBAGE").unwrap();
+        assert_eq!(Some(200), r.head.code);
+        assert_eq!(b"hello", &r.body[..])
+    }
+
+    #[test]
+    fn chunked() {
+        let bytes = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let r = parse(&bytes[..]).unwrap();
+        assert_eq!(b"hello, world", &r.body[..])
+    }
+
+    #[test]
+    fn skip_continue() {
+        let bytes = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let r = parse(&bytes[..]).unwrap();
+        assert_eq!(Some(200), r.head.code);
+        assert_eq!(b"hello", &r.body[..])
+    }
+
+    /// Yields one byte per read.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0)
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1 ..];
+            Ok(1)
+        }
+    }
+
+    impl AsyncRead for Trickle<'_> {}
+
+    #[test]
+    fn trickle() {
+        let bytes = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        let r = read_response(Trickle(&bytes[..]), 1024).wait().unwrap();
+        assert_eq!(Some("chunked"), r.head.header("transfer-encoding"));
+        assert_eq!(b"hello, world", &r.body[..]);
+
+        let bytes = b"HTTP/1.0 200 OK\n\nhello";
+        let r = read_response(Trickle(&bytes[..]), 1024).wait().unwrap();
+        assert_eq!(b"hello", &r.body[..])
+    }
+
+    #[test]
+    fn until_close() {
+        let r = parse(b"HTTP/1.0 200 OK\r\nServer: test\r\n\r\nhello").unwrap();
+        assert_eq!(Some("test"), r.head.header("server"));
+        assert_eq!(b"hello", &r.body[..])
+    }
+
+    #[test]
+    fn many_headers() {
+        let mut bytes = b"HTTP/1.1 200 OK\r\n".to_vec();
+        for i in 0 .. 100 {
+            bytes.extend_from_slice(format!("X-Header-{}: {}\r\n", i, i).as_bytes())
+        }
+        bytes.extend_from_slice(b"Content-Length: 0\r\n\r\n");
+        let r = read_response(&bytes[..], 8192).wait().unwrap();
+        assert_eq!(101, r.head.headers.len())
+    }
+
+    #[test]
+    fn incomplete() {
+        match parse(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello") {
+            Err(Error::Incomplete) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        match parse(b"HTTP/1.1 200 OK\r\nContent-") {
+            Err(Error::Incomplete) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let body = vec![b'x'; 2048];
+        let mut bytes = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        bytes.extend_from_slice(&body);
+        match parse(&bytes[..]) {
+            Err(Error::TooLarge(1024)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+}
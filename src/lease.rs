@@ -0,0 +1,287 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{Control, ErrorCode, Igdp, Protocol, error::Error};
+use futures::{prelude::*, try_ready};
+use log::{debug, trace, warn};
+use std::{fmt, time::{Duration, Instant}};
+use tokio_executor::Executor;
+use tokio_timer::Delay;
+
+/// How often permanent port mappings are checked for their existence.
+const PERMANENT_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The shortest interval between two renewals.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events reported by a `PortMappingLease`.
+#[derive(Debug)]
+pub enum LeaseEvent {
+    /// The port mapping has been renewed.
+    Renewed,
+    /// The gateway had lost the port mapping (e.g. after a reboot) and it has
+    /// been created again.
+    Recreated,
+    /// The port mapping has been renewed, but checking it beforehand failed,
+    /// e.g. because the gateway does not support `GetSpecificPortMappingEntry`.
+    Unverified(Error),
+    /// Renewing the port mapping failed. Another attempt will be made, unless
+    /// the error is `Error::MappingMismatch`, which ends the stream.
+    Failed(Error)
+}
+
+/// A port mapping which renews itself.
+///
+/// The lease is a `Stream` which needs to be polled to renew the port mapping
+/// after half of its lease duration has passed. Failed renewals are retried
+/// after an eighth of the lease duration. Port mappings without a lease
+/// duration are permanent and only checked for their existence from time to
+/// time.
+///
+/// If the gateway maps the external port to another internal client or port,
+/// the mapping is not ours anymore. The lease then reports
+/// `LeaseEvent::Failed(Error::MappingMismatch(..))`, ends the stream and
+/// leaves the mapping alone.
+///
+/// Dropping the lease removes the port mapping in the background, `close`
+/// removes it explicitly. Dropping it outside of a tokio runtime leaks the
+/// port mapping until its lease duration ends, use `close` there instead.
+pub struct PortMappingLease {
+    igdp: Igdp<Control>,
+    protocol: Protocol,
+    external: u16,
+    port: u16,
+    duration: Duration,
+    description: String,
+    delay: Delay,
+    pending: Option<Box<dyn Future<Item=LeaseEvent, Error=Error> + Send>>,
+    /// The port mapping has been removed or is not ours anymore.
+    closed: bool,
+    /// The stream has ended.
+    done: bool
+}
+
+impl fmt::Debug for PortMappingLease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PortMappingLease")
+            .field("protocol", &self.protocol)
+            .field("external", &self.external)
+            .field("port", &self.port)
+            .field("duration", &self.duration)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+impl PortMappingLease {
+    pub(crate) fn new(
+        igdp: Igdp<Control>,
+        protocol: Protocol,
+        external: u16,
+        port: u16,
+        duration: Duration,
+        description: String
+    ) -> Self {
+        let mut lease = PortMappingLease {
+            igdp,
+            protocol,
+            external,
+            port,
+            duration,
+            description,
+            delay: Delay::new(Instant::now()),
+            pending: None,
+            closed: false,
+            done: false
+        };
+        lease.delay.reset(Instant::now() + lease.interval());
+        lease
+    }
+
+    /// The external port of the port mapping.
+    pub fn external_port(&self) -> u16 {
+        self.external
+    }
+
+    /// The protocol of the port mapping.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Stop renewing and remove the port mapping.
+    pub fn close(mut self) -> impl Future<Item=(), Error=Error> {
+        self.closed = true;
+        self.igdp.clone().delete_port_mapping(self.protocol, self.external, None).map(|_| ())
+    }
+
+    /// The time between successful renewals.
+    fn interval(&self) -> Duration {
+        if self.duration == Duration::from_secs(0) {
+            PERMANENT_CHECK_INTERVAL
+        } else {
+            std::cmp::max(self.duration / 2, MIN_INTERVAL)
+        }
+    }
+
+    /// The time between failed and next renewal.
+    fn retry_interval(&self) -> Duration {
+        if self.duration == Duration::from_secs(0) {
+            PERMANENT_CHECK_INTERVAL
+        } else {
+            std::cmp::max(self.duration / 8, MIN_INTERVAL)
+        }
+    }
+
+    /// Check if the port mapping still exists and renew or recreate it.
+    fn renew(&self) -> Box<dyn Future<Item=LeaseEvent, Error=Error> + Send> {
+        let igdp = self.igdp.clone();
+        let local = self.igdp.local;
+        let (proto, external, port) = (self.protocol, self.external, self.port);
+        let (duration, description) = (self.duration, self.description.clone());
+        let future = self.igdp.clone().get_specific_port_mapping(proto, external, None)
+            .then(move |result| {
+                match result {
+                    Ok((_, entry)) => {
+                        if entry.internal_client != local || entry.internal_port != port {
+                            return Err(Error::MappingMismatch(Box::new(entry)))
+                        }
+                        Ok(LeaseEvent::Renewed)
+                    }
+                    Err(Error::Upnp(ErrorCode::NoSuchEntryInArray, _)) => {
+                        debug!("gateway lost {} port mapping {}", proto, external);
+                        Ok(LeaseEvent::Recreated)
+                    }
+                    Err(e) => {
+                        debug!("failed to look up {} port mapping {}: {}", proto, external, e);
+                        Ok(LeaseEvent::Unverified(e))
+                    }
+                }
+            })
+            .and_then(move |event| {
+                igdp.add_fixed_port_mapping(proto, external, port, duration, &description)
+                    .map(move |_| event)
+            });
+        Box::new(future)
+    }
+}
+
+impl Stream for PortMappingLease {
+    type Item = LeaseEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None))
+        }
+        loop {
+            if let Some(mut future) = self.pending.take() {
+                match future.poll() {
+                    Ok(Async::NotReady) => {
+                        self.pending = Some(future);
+                        return Ok(Async::NotReady)
+                    }
+                    Ok(Async::Ready(event)) => {
+                        trace!("{} port mapping {}: {:?}", self.protocol, self.external, event);
+                        self.delay.reset(Instant::now() + self.interval());
+                        return Ok(Async::Ready(Some(event)))
+                    }
+                    Err(e @ Error::MappingMismatch(_)) => {
+                        let (proto, external) = (self.protocol, self.external);
+                        debug!("lost {} port mapping {} to another client", proto, external);
+                        self.closed = true;
+                        self.done = true;
+                        return Ok(Async::Ready(Some(LeaseEvent::Failed(e))))
+                    }
+                    Err(e) => {
+                        let (proto, external) = (self.protocol, self.external);
+                        debug!("failed to renew {} port mapping {}: {}", proto, external, e);
+                        self.delay.reset(Instant::now() + self.retry_interval());
+                        return Ok(Async::Ready(Some(LeaseEvent::Failed(e))))
+                    }
+                }
+            }
+            try_ready!(self.delay.poll().map_err(|_| Error::Timer));
+            self.pending = Some(self.renew())
+        }
+    }
+}
+
+impl Drop for PortMappingLease {
+    fn drop(&mut self) {
+        if self.closed {
+            return
+        }
+        let (proto, external) = (self.protocol, self.external);
+        let future = self.igdp.clone().delete_port_mapping(proto, external, None)
+            .map(move |_| trace!("removed {} port mapping {}", proto, external))
+            .map_err(move |e| {
+                debug!("failed to remove {} port mapping {}: {}", proto, external, e)
+            });
+        if let Err(e) = tokio_executor::DefaultExecutor::current().spawn(Box::new(future)) {
+            warn!("failed to spawn removal of {} port mapping {}: {:?}", proto, external, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(duration: Duration) -> PortMappingLease {
+        lease_at("192.168.1.1:5000".parse().unwrap(), duration)
+    }
+
+    fn lease_at(addr: std::net::SocketAddr, duration: Duration) -> PortMappingLease {
+        let igdp = crate::util::tests::igdp_at(addr);
+        let mut lease = PortMappingLease::new(igdp, Protocol::Tcp, 4000, 4000, duration, "test".into());
+        lease.closed = true;
+        lease
+    }
+
+    #[test]
+    fn renewal_intervals() {
+        let l = lease(Duration::from_secs(3600));
+        assert_eq!(Duration::from_secs(1800), l.interval());
+        assert_eq!(Duration::from_secs(450), l.retry_interval());
+
+        let l = lease(Duration::from_secs(1));
+        assert_eq!(MIN_INTERVAL, l.interval());
+        assert_eq!(MIN_INTERVAL, l.retry_interval());
+
+        let l = lease(Duration::from_secs(0));
+        assert_eq!(PERMANENT_CHECK_INTERVAL, l.interval())
+    }
+
+    #[test]
+    fn end_on_mismatch() {
+        let (addr, handle) = crate::util::tests::soap_server("<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+            <u:GetSpecificPortMappingEntryResponse \
+            xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:2\">\
+            <NewInternalPort>4000</NewInternalPort>\
+            <NewInternalClient>192.168.1.99</NewInternalClient>\
+            </u:GetSpecificPortMappingEntryResponse></s:Body></s:Envelope>");
+        let mut lease = lease_at(addr, Duration::from_secs(1));
+        lease.closed = false;
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (event, lease) = rt.block_on(lease.into_future()).map_err(|(e, _)| e).unwrap();
+        match event {
+            Some(LeaseEvent::Failed(Error::MappingMismatch(entry))) => {
+                let client: std::net::IpAddr = "192.168.1.99".parse().unwrap();
+                assert_eq!(client, entry.internal_client)
+            }
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert!(lease.closed);
+        let (event, _) = rt.block_on(lease.into_future()).map_err(|(e, _)| e).unwrap();
+        assert!(event.is_none());
+        handle.join().unwrap()
+    }
+}
@@ -10,51 +10,156 @@
 
 #![forbid(unsafe_code)]
 
+mod builder;
+mod description;
 mod error;
+mod firewall;
+mod http;
+mod lease;
+mod mapper;
+mod natpmp;
+mod notify;
+mod pcp;
+mod ssdp;
 mod util;
 mod xml;
 
-use crate::{error::{Error, Result}, util::{SSDP_SEARCH_REQUEST, SERVICE_TYPE}};
+pub use crate::{
+    builder::IgdpBuilder,
+    description::{Device, DeviceDescription, Service},
+    error::{Error, ErrorCode, FirewallErrorCode, NatPmpCode, PcpCode, Step},
+    firewall::{Firewall, FirewallStatus, Pinhole, UniqueId},
+    lease::{LeaseEvent, PortMappingLease},
+    mapper::{BoxFuture, Mapping, Mechanism, PortMapper, port_mapper, port_mapper_within},
+    natpmp::{NatPmp, NatPmpMapping},
+    notify::{Announcements, GatewayEvent},
+    pcp::{Filter, MapRequest, Nonce, Pcp, PcpMapping, PeerRequest},
+    ssdp::{SearchResponse, Server}
+};
+
+use crate::{error::Result, util::CONNECTION_SERVICES};
 use futures::{future::{self, Either, Loop}, prelude::*};
 use log::{debug, trace};
 use roxmltree::Document;
-use std::{fmt, net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs}, str, time::{Duration, Instant}};
-use tokio_timer::Delay;
+use std::{collections::HashSet, fmt, net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs}, str, time::Duration};
 use tokio_udp::UdpSocket;
 use unicase::Ascii;
 use url::Url;
 
+/// The default overall deadline of `external_ip`, `port_mapping`, `remove_port_mapping`
+/// and `port_mapper`.
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(30);
+
 /// Try to get our external IP address form a UPnP WANIPConnection.
+///
+/// Fails with `Error::Timeout(Step::Deadline)` after `DEFAULT_DEADLINE`, which
+/// earlier versions did not impose. Use `external_ip_within` for another deadline.
 pub fn external_ip<A>(addrs: A) -> impl Future<Item=Option<IpAddr>, Error=Error>
 where
     A: ToSocketAddrs
 {
-    future::result(Igdp::bind(addrs))
+    external_ip_within(addrs, DEFAULT_DEADLINE)
+}
+
+/// Like `external_ip` but fails with `Error::Timeout(Step::Deadline)` after `deadline`.
+pub fn external_ip_within<A>(addrs: A, deadline: Duration)
+    -> impl Future<Item=Option<IpAddr>, Error=Error>
+where
+    A: ToSocketAddrs
+{
+    let f = future::result(Igdp::bind(addrs))
         .and_then(Igdp::discover)
         .and_then(Igdp::control)
         .and_then(Igdp::external_ip)
-        .map(|(_, addr)| addr)
+        .map(|(_, addr)| addr);
+    util::timeout(f, deadline, Step::Deadline)
 }
 
 /// Try to create a port mapping for any external host to the given port.
+///
+/// Fails with `Error::Timeout(Step::Deadline)` after `DEFAULT_DEADLINE`, which
+/// earlier versions did not impose. Use `port_mapping_within` for another deadline.
 pub fn port_mapping<A>(addrs: A, p: Protocol, port: u16, dur: Duration, descr: &'static str)
     -> impl Future<Item=Option<u16>, Error=Error>
 where
     A: ToSocketAddrs
 {
-    future::result(Igdp::bind(addrs))
+    port_mapping_within(addrs, p, port, dur, descr, DEFAULT_DEADLINE)
+}
+
+/// Like `port_mapping` but fails with `Error::Timeout(Step::Deadline)` after `deadline`.
+pub fn port_mapping_within<A>(
+    addrs: A,
+    p: Protocol,
+    port: u16,
+    dur: Duration,
+    descr: &'static str,
+    deadline: Duration
+) -> impl Future<Item=Option<u16>, Error=Error>
+where
+    A: ToSocketAddrs
+{
+    let f = future::result(Igdp::bind(addrs))
         .and_then(Igdp::discover)
         .and_then(Igdp::control)
         .and_then(move |igdp| {
             igdp.add_port_mapping(p, port, dur, descr)
         })
-        .map(|(_, port)| port)
+        .map(|(_, port)| port);
+    util::timeout(f, deadline, Step::Deadline)
+}
+
+/// Try to remove the port mapping of the given external port.
+///
+/// Fails with `Error::Timeout(Step::Deadline)` after `DEFAULT_DEADLINE`.
+/// Use `remove_port_mapping_within` for another deadline.
+pub fn remove_port_mapping<A>(addrs: A, p: Protocol, port: u16) -> impl Future<Item=(), Error=Error>
+where
+    A: ToSocketAddrs
+{
+    remove_port_mapping_within(addrs, p, port, DEFAULT_DEADLINE)
+}
+
+/// Like `remove_port_mapping` but fails with `Error::Timeout(Step::Deadline)` after `deadline`.
+pub fn remove_port_mapping_within<A>(addrs: A, p: Protocol, port: u16, deadline: Duration)
+    -> impl Future<Item=(), Error=Error>
+where
+    A: ToSocketAddrs
+{
+    let f = future::result(Igdp::bind(addrs))
+        .and_then(Igdp::discover)
+        .and_then(Igdp::control)
+        .and_then(move |igdp| {
+            igdp.delete_port_mapping(p, port, None)
+        })
+        .map(|_| ());
+    util::timeout(f, deadline, Step::Deadline)
 }
 
 /// The protocol for which a port mapping should be created.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Protocol { Tcp, Udp }
 
+impl Protocol {
+    fn parse(s: &str) -> Option<Self> {
+        if Ascii::new(s) == "TCP" {
+            Some(Protocol::Tcp)
+        } else if Ascii::new(s) == "UDP" {
+            Some(Protocol::Udp)
+        } else {
+            None
+        }
+    }
+
+    /// The IANA protocol number.
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17
+        }
+    }
+}
+
 impl fmt::Display for Protocol {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -64,164 +169,405 @@ impl fmt::Display for Protocol {
     }
 }
 
+/// A port mapping as stored by the gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortMappingEntry {
+    /// The remote host this mapping applies to, `None` means any host.
+    pub remote_host: Option<IpAddr>,
+    /// The external port.
+    pub external_port: u16,
+    /// The protocol of this mapping.
+    pub protocol: Protocol,
+    /// The address of the host traffic is forwarded to.
+    pub internal_client: IpAddr,
+    /// The port traffic is forwarded to.
+    pub internal_port: u16,
+    /// Whether this mapping is enabled.
+    pub enabled: bool,
+    /// The mapping's description.
+    pub description: String,
+    /// The remaining lease, zero means the mapping is permanent.
+    pub lease_duration: Duration
+}
+
 /// An instance of the IGD protocol.
 #[derive(Debug)]
 pub struct Igdp<T> {
-    socket: UdpSocket,
     local: IpAddr,
-    buffer: Vec<u8>,
+    config: Config,
+    /// The socket used for discovery, only present in the initial `Igdp<()>`.
+    bound: Option<Bound>,
     state: T
 }
 
+impl Clone for Igdp<Control> {
+    fn clone(&self) -> Self {
+        let state = self.state.clone();
+        Igdp { local: self.local, config: self.config.clone(), bound: None, state }
+    }
+}
+
+/// Settings which apply to all `Igdp` states.
+#[derive(Clone, Debug)]
+struct Config {
+    /// Upper bound of HTTP response sizes in bytes.
+    max_response_size: usize,
+    /// How long to wait for TCP connections to be established.
+    connect_timeout: Duration,
+    /// How long to wait for HTTP requests to be sent.
+    send_timeout: Duration,
+    /// How long to wait for HTTP responses to be received.
+    receive_timeout: Duration,
+    /// Whether control URLs must point to the host of the device description.
+    validate_location: bool
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_response_size: 1024 * 1024,
+            connect_timeout: Duration::from_secs(5),
+            send_timeout: Duration::from_secs(5),
+            receive_timeout: Duration::from_secs(10),
+            validate_location: true
+        }
+    }
+}
+
+/// The UDP socket and settings of an `Igdp<()>` instance used for discovery.
+#[derive(Debug)]
+struct Bound {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+    search: ssdp::SearchConfig
+}
+
 /// `Igdp` state after discovery was successful.
 #[derive(Debug)]
 pub struct Discovery {
     url: Url,
-    addr: SocketAddr
+    addr: SocketAddr,
+    /// The M-SEARCH response we discovered the gateway with.
+    response: Option<SearchResponse>
+}
+
+impl Discovery {
+    /// The scope ID to resolve link-local URLs of the gateway with.
+    fn scope_id(&self) -> u32 {
+        match self.addr {
+            SocketAddr::V6(addr) => addr.scope_id(),
+            SocketAddr::V4(_) => 0
+        }
+    }
 }
 
 /// `Igdp` state after a control URL has been discovered.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Control {
     url: Url,
-    addr: SocketAddr
+    addr: SocketAddr,
+    /// The service type, e.g. `urn:schemas-upnp-org:service:WANPPPConnection:1`.
+    service: String,
+    /// Read back new port mappings to check that they are as requested.
+    verify: bool
+}
+
+impl Control {
+    /// The version of the service we control.
+    fn version(&self) -> u32 {
+        util::service_version(&self.service)
+    }
+}
+
+impl<T> Igdp<T> {
+    /// The address port mappings forward traffic to.
+    ///
+    /// Unless set explicitly, this is the address we are bound to or, if that
+    /// is unspecified, the address we use to reach the gateway.
+    pub fn internal_client(&self) -> IpAddr {
+        self.local
+    }
+
+    /// Override the address port mappings forward traffic to.
+    pub fn set_internal_client(&mut self, addr: IpAddr) {
+        self.local = addr
+    }
+
+    /// Set the maximum size in bytes of HTTP responses we accept from the gateway.
+    ///
+    /// Larger responses fail with `Error::TooLarge`. The default is 1 MiB.
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.config.max_response_size = size
+    }
+
+    /// Set how long to wait for a TCP connection to the gateway. The default is 5s.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.config.connect_timeout = timeout
+    }
+
+    /// Set how long to wait for a request to be sent to the gateway. The default is 5s.
+    pub fn set_send_timeout(&mut self, timeout: Duration) {
+        self.config.send_timeout = timeout
+    }
+
+    /// Set how long to wait for a response of the gateway. The default is 10s.
+    pub fn set_receive_timeout(&mut self, timeout: Duration) {
+        self.config.receive_timeout = timeout
+    }
+
+    /// Enable or disable the check that control URLs point to the host of the
+    /// device description. Enabled by default, see `IgdpBuilder::validate_location`.
+    pub fn set_validate_location(&mut self, enabled: bool) {
+        self.config.validate_location = enabled
+    }
 }
 
 impl Igdp<()> {
     /// Create a new Igdp instance, binding the UDP port to the address provided.
+    ///
+    /// When bound to an IPv6 address, discovery uses the link-local and
+    /// site-local SSDP groups on the interface of the address's scope ID.
+    /// Use `IgdpBuilder` to change the discovery settings.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        for a in addr.to_socket_addrs()? {
-            if let Ok(socket) = UdpSocket::bind(&a) {
-                let local = socket.local_addr()?;
-                trace!("new igdp instance bound to {}", local);
-                return Ok(Igdp {
-                    socket,
-                    local: local.ip(),
-                    buffer: vec![0; 65527],
-                    state: ()
-                })
-            }
-        }
-        Err(Error::Bind)
+        IgdpBuilder::new().bind(addr)
     }
 
-    /// Send SSDP M-SEARCH request to find a UPnP `WANIPConnection`.
+    /// Send SSDP M-SEARCH requests to find a UPnP internet gateway.
+    ///
+    /// Resolves to the best ranked gateway which responded during the first
+    /// discovery round that produced any response. If the location of a
+    /// gateway cannot be resolved, the next best ranked gateway is tried.
     pub fn discover(self) -> impl Future<Item=Igdp<Discovery>, Error=Error> {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
-        let buff = self.buffer;
-        let sock = self.socket;
-        let local = self.local;
-
-        // Send M-SEARCH request up to three times and wait 1 sec for response.
-        // Since we use UDP, frames may get lost, so retrying seems advisable.
-        future::loop_fn((1, sock, buff), move |(i, sock, buff)| {
-            if i > 3 {
-                return Either::A(future::err(Error::Timeout))
-            }
-            Either::B(sock.send_dgram(SSDP_SEARCH_REQUEST, &addr).from_err()
-                .and_then(move |(sock, _)| {
-                    trace!("sent m-search request to {}", addr);
-                    sock.recv_dgram(buff)
-                        .select2(Delay::new(Instant::now() + Duration::from_secs(1)))
-                        .map_err(|error| {
-                            match error {
-                                Either::A((e, _)) => e.into(),
-                                Either::B((_, _)) => Error::Timer
-                            }
-                        })
-                        .and_then(move |result| {
-                            match result {
-                                Either::A((result, _)) => {
-                                    Ok(Loop::Break(result))
-                                }
-                                Either::B((_, recv)) => {
-                                    let parts = recv.into_parts();
-                                    Ok(Loop::Continue((i + 1, parts.socket, parts.buffer)))
-                                }
+        let (local, config) = (self.local, self.config);
+        let bound = self.bound.expect("`Igdp<()>` is created by `IgdpBuilder::bind`");
+        let targets = bound.search.targets.clone();
+        ssdp::Search::new(bound.socket, bound.buffer, bound.search)
+            .first_round()
+            .collect()
+            .and_then(move |mut responses| {
+                responses.sort_by_key(|(_, r)| r.rank(&targets));
+                let error = Error::Timeout(Step::Discovery);
+                future::loop_fn((responses.into_iter(), error), move |(mut responses, error)| {
+                    let (from, response) = match responses.next() {
+                        Some(next) => next,
+                        None => return Either::A(future::err(error))
+                    };
+                    let igdp = Igdp::discovered(local, config.clone(), from, response);
+                    Either::B(igdp.then(move |r| {
+                        match r {
+                            Ok(igdp) => Ok(Loop::Break(igdp)),
+                            Err(e) => {
+                                debug!("ignoring m-search response from {}: {}", from, e);
+                                Ok(Loop::Continue((responses, e)))
                             }
+                        }
+                    }))
+                })
+            })
+    }
+
+    /// Send SSDP M-SEARCH requests to find every UPnP internet gateway.
+    ///
+    /// In contrast to `Igdp::discover` this listens for responses during the
+    /// whole discovery window and resolves to all gateways found, which may
+    /// be none. Every gateway is reported once, best ranked gateways first.
+    pub fn discover_all(self) -> impl Future<Item=Vec<Igdp<Discovery>>, Error=Error> {
+        let (local, config) = (self.local, self.config);
+        let bound = self.bound.expect("`Igdp<()>` is created by `IgdpBuilder::bind`");
+        let targets = bound.search.targets.clone();
+        ssdp::Search::new(bound.socket, bound.buffer, bound.search)
+            .collect()
+            .and_then(move |mut responses| {
+                responses.sort_by_key(|(_, r)| r.rank(&targets));
+                let mut locations = HashSet::new();
+                let gateways: Vec<_> = responses.into_iter()
+                    .filter(|(_, r)| locations.insert(r.location.clone()))
+                    .map(|(from, response)| {
+                        Igdp::discovered(local, config.clone(), from, response).then(move |r| {
+                            let r = r.map_err(|e| {
+                                debug!("ignoring m-search response from {}: {}", from, e)
+                            });
+                            Ok(r.ok())
                         })
-                }))
-        })
-        .and_then(move |(sock, buf, n, addr)| {
-            trace!("received m-search response from {}", addr);
-            let url;
-            {
-                let mut headers = [httparse::EMPTY_HEADER; 16];
-                let mut response = httparse::Response::new(&mut headers);
-                let mut location = None;
-                response.parse(&buf[.. n])?; // TODO: handle partial
-                if Some(200) != response.code {
-                    debug!("m-search response code = {:?}", response.code);
-                    return Err(Error::StatusCode(response.code))
-                }
-                for h in response.headers {
-                    if Ascii::new(h.name) == "LOCATION" {
-                        location = Some(h.value);
-                        break
-                    }
-                }
-                if let Some(u) = location
-                    .and_then(|loc| str::from_utf8(loc).ok())
-                    .and_then(|loc| Url::parse(loc).ok())
-                {
-                    url = u
-                } else {
-                    return Err(Error::Location)
-                }
-            }
-            trace!("discovered location: {}", url);
-            let addr = util::url2sock(&url)?;
-            let disco = Discovery { url, addr };
-            Ok(Igdp {
-                socket: sock,
-                buffer: buf,
-                local,
-                state: disco
+                    })
+                    .collect();
+                future::join_all(gateways).map(|gateways| gateways.into_iter().flatten().collect())
             })
-        })
     }
 }
 
 impl Igdp<Discovery> {
-    /// After we have found an WANIPConnection endpoint, try you figure out
-    /// its control URL.
+    /// Create an Igdp instance for the gateway whose device description is
+    /// at the given location, without sending any M-SEARCH requests.
+    ///
+    /// `bind` is the local address port mappings forward traffic to. If it is
+    /// unspecified, the address we use to reach the gateway is used instead.
+    ///
+    /// The location is usually a `Url`. It may use a host name, which is
+    /// resolved on a new thread, and, given as string, a link-local IPv6
+    /// address with zone ID, e.g. `http://[fe80::1%eth0]:5000/`. Only `http`
+    /// URLs are supported.
+    pub fn from_location<U>(bind: IpAddr, location: U) -> impl Future<Item=Self, Error=Error>
+    where
+        U: AsRef<str>
+    {
+        match util::parse_url(location.as_ref()) {
+            Ok((url, scope_id)) => Either::A(Igdp::located(bind, Config::default(), url, scope_id)),
+            Err(e) => Either::B(future::err(e))
+        }
+    }
+
+    fn discovered(local: IpAddr, config: Config, from: SocketAddr, response: SearchResponse)
+        -> impl Future<Item=Self, Error=Error>
+    {
+        trace!("discovered location {} from {}", response.location, from);
+        let scope_id = match from {
+            SocketAddr::V6(from) => from.scope_id(),
+            SocketAddr::V4(_) => 0
+        };
+        Igdp::located(local, config, response.location.clone(), scope_id)
+            .map(move |mut igdp| {
+                igdp.state.response = Some(response);
+                igdp
+            })
+    }
+
+    fn located(local: IpAddr, config: Config, url: Url, scope_id: u32)
+        -> impl Future<Item=Self, Error=Error>
+    {
+        util::resolve(&url, scope_id).map(move |addr| {
+            let local = internal_client(local, addr);
+            Igdp { local, config, bound: None, state: Discovery { url, addr, response: None } }
+        })
+    }
+
+    /// The location of the gateway's device description.
+    pub fn location(&self) -> &Url {
+        &self.state.url
+    }
+
+    /// The M-SEARCH response of the gateway, unless created with `Igdp::from_location`.
+    pub fn search_response(&self) -> Option<&SearchResponse> {
+        self.state.response.as_ref()
+    }
+
+    /// Get the device description of the gateway.
+    pub fn description(self) -> impl Future<Item=(Self, DeviceDescription), Error=Error> {
+        fetch_description(&self.state, &self.config)
+            .map(move |description| (self, description))
+    }
+
+    /// After we have found an internet gateway, try to figure out the control
+    /// URL of its `WANIPConnection` or `WANPPPConnection` service.
+    ///
+    /// If the gateway offers more than one such service, the first one whose
+    /// `GetStatusInfo` reports `Connected` is chosen. Control URLs pointing to
+    /// another host than the device description are ignored, unless disabled
+    /// with `set_validate_location`.
     pub fn control(self) -> impl Future<Item=Igdp<Control>, Error=Error> {
-        let req = util::format_get_req(&self.state.addr, self.state.url.path());
-        trace!("connecting to {}", self.state.addr);
-        util::fetch(self.state.addr, req)
-            .and_then(move |bytes| {
-                let url = extract_control_url(self.state.url, &bytes[..])?;
-                trace!("extracted control url {}", url);
-                Ok(Igdp {
-                    socket: self.socket,
-                    buffer: self.buffer,
-                    local: self.local,
-                    state: Control { url, addr: self.state.addr }
-                })
+        let (local, config) = (self.local, self.config);
+        let status_config = config.clone();
+        let (scope_id, gateway) = (self.state.scope_id(), self.state.addr);
+        let validate = config.validate_location;
+        fetch_description(&self.state, &config)
+            .and_then(|description| extract_control_urls(&description))
+            .and_then(move |services| {
+                let candidates = services.into_iter().map(move |(url, service)| {
+                    util::resolve(&url, scope_id).then(move |addr| {
+                        let addr = addr.and_then(|a| {
+                            if validate {
+                                util::check_control_addr(&url, a, gateway)?
+                            }
+                            Ok(a)
+                        });
+                        match addr {
+                            Ok(addr) => Ok(Some(Control { url, addr, service, verify: false })),
+                            Err(e) => {
+                                debug!("ignoring control url {}: {}", url, e);
+                                Ok(None)
+                            }
+                        }
+                    })
+                });
+                future::join_all(candidates)
+            })
+            .and_then(move |candidates| {
+                let mut candidates: Vec<Control> = candidates.into_iter().flatten().collect();
+                if candidates.len() < 2 {
+                    return Either::A(future::result(candidates.pop().ok_or(Error::ControlUrl)))
+                }
+                let statuses: Vec<_> = candidates.into_iter()
+                    .map(|ctrl| {
+                        connection_status(&ctrl, &status_config).then(|status| Ok((ctrl, status)))
+                    })
+                    .collect();
+                Either::B(future::join_all(statuses).and_then(|mut statuses| {
+                    for (ctrl, status) in &statuses {
+                        trace!("{} at {} has status: {:?}", ctrl.service, ctrl.url, status)
+                    }
+                    let connected = statuses.iter().position(|(_, status)| match status {
+                        Ok(Some(s)) => s == "Connected",
+                        _ => false
+                    });
+                    Ok(statuses.swap_remove(connected.unwrap_or(0)).0)
+                }))
+            })
+            .map(move |ctrl| {
+                trace!("extracted control url {} of {}", ctrl.url, ctrl.service);
+                Igdp { local, config, bound: None, state: ctrl }
             })
     }
 }
 
 impl Igdp<Control> {
+    /// Create an Igdp instance for a known control URL and service type,
+    /// e.g. `urn:schemas-upnp-org:service:WANIPConnection:1`.
+    ///
+    /// Port mappings forward traffic to the address we use to reach the
+    /// gateway, unless changed with `Igdp::set_internal_client`. Host names
+    /// and zone IDs are supported as in `Igdp::from_location`.
+    pub fn from_control<U>(url: U, service_type: &str) -> impl Future<Item=Self, Error=Error>
+    where
+        U: AsRef<str>
+    {
+        let (url, scope_id) = match util::parse_url(url.as_ref()) {
+            Ok(x) => x,
+            Err(e) => return Either::A(future::err(e))
+        };
+        let service = service_type.to_string();
+        Either::B(util::resolve(&url, scope_id).map(move |addr| {
+            let local = internal_client(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr);
+            let state = Control { url, addr, service, verify: false };
+            Igdp { local, config: Config::default(), bound: None, state }
+        }))
+    }
+
     /// Get our external IP address.
     pub fn external_ip(self) -> impl Future<Item=(Self, Option<IpAddr>), Error=Error> {
-        let req = util::format_external_ip(&self.state.addr, self.state.url.path());
+        let req = util::format_external_ip(&self.state);
         trace!("connecting to {}", self.state.addr);
-        util::fetch(self.state.addr, req)
-            .and_then(move |bytes| {
-                let ext_ip = extract_external_ip(&bytes[..])?;
+        util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| {
+                let ext_ip = extract_external_ip(&response)?;
                 trace!("external IP address: {:?}", ext_ip);
-                let igdp = Igdp {
-                    socket: self.socket,
-                    buffer: self.buffer,
-                    local: self.local,
-                    state: self.state
-                };
-                Ok((igdp, ext_ip))
+                Ok((self, ext_ip))
             })
     }
 
+    /// Enable or disable the verification of new port mappings.
+    ///
+    /// When enabled, `add_port_mapping` reads every new mapping back from the
+    /// gateway and fails with `Error::MappingMismatch` unless it forwards to
+    /// the requested internal client, port and protocol.
+    pub fn verify_mappings(mut self, enabled: bool) -> Self {
+        self.state.verify = enabled;
+        self
+    }
+
     /// Try to create a port mapping, allowing incoming traffic to reach us at the given port.
+    ///
+    /// IGD v2 gateways may choose a different external port, IGD v1 gateways
+    /// (which lack `AddAnyPortMapping`) map the same port number externally.
     pub fn add_port_mapping(self, proto: Protocol, port: u16, dura: Duration, description: &str)
         -> impl Future<Item=(Self, Option<u16>), Error=Error>
     {
@@ -232,99 +578,401 @@ impl Igdp<Control> {
             duration: dura,
             description
         };
-        let req = util::format_add_any_port_mapping(&self.state.addr, self.state.url.path(), &pmap);
+        let any = self.state.version() >= 2;
+        let req = if any {
+            util::format_add_any_port_mapping(&self.state, &pmap)
+        } else {
+            util::format_add_port_mapping(&self.state, &pmap, port)
+        };
         trace!("connecting to {}", self.state.addr);
-        util::fetch(self.state.addr, req)
-            .and_then(move |bytes| {
-                let port = extract_port_mapping(&bytes[..])?;
-                trace!("external port: {:?}", port);
-                let igdp = Igdp {
-                    socket: self.socket,
-                    buffer: self.buffer,
-                    local: self.local,
-                    state: self.state
+        util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| {
+                let port = if any {
+                    extract_port_mapping(&response)?
+                } else {
+                    extract_soap_response(&response, "AddPortMapping", |_| ())?;
+                    Some(port)
                 };
-                Ok((igdp, port))
+                trace!("external port: {:?}", port);
+                Ok((self, port))
+            })
+            .and_then(move |(igdp, external)| {
+                match external {
+                    Some(external) if igdp.state.verify => {
+                        let local = igdp.local;
+                        Either::A(igdp.get_specific_port_mapping(proto, external, None)
+                            .and_then(move |(igdp, entry)| {
+                                let matches = entry.internal_client == local
+                                    && entry.internal_port == port
+                                    && entry.protocol == proto;
+                                if !matches {
+                                    debug!("port mapping differs from request: {:?}", entry);
+                                    return Err(Error::MappingMismatch(Box::new(entry)))
+                                }
+                                Ok((igdp, Some(external)))
+                            }))
+                    }
+                    _ => Either::B(future::ok((igdp, external)))
+                }
+            })
+    }
+
+    /// Create a port mapping which is renewed until the lease is dropped or closed.
+    ///
+    /// See `PortMappingLease` for details.
+    pub fn lease(self, proto: Protocol, port: u16, dura: Duration, description: &str)
+        -> impl Future<Item=PortMappingLease, Error=Error>
+    {
+        let description = description.to_string();
+        self.add_port_mapping(proto, port, dura, &description)
+            .map(move |(igdp, external)| {
+                let external = external.unwrap_or_else(|| {
+                    debug!("gateway did not report external port, assuming {}", port);
+                    port
+                });
+                PortMappingLease::new(igdp, proto, external, port, dura, description)
+            })
+    }
+
+    /// Create or refresh the port mapping of the given external port.
+    pub(crate) fn add_fixed_port_mapping(
+        self,
+        proto: Protocol,
+        external: u16,
+        port: u16,
+        dura: Duration,
+        description: &str
+    ) -> impl Future<Item=Self, Error=Error> {
+        let pmap = util::PortMapping {
+            protocol: proto,
+            address: self.local,
+            port,
+            duration: dura,
+            description
+        };
+        let req = util::format_add_port_mapping(&self.state, &pmap, external);
+        trace!("connecting to {}", self.state.addr);
+        util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| {
+                extract_soap_response(&response, "AddPortMapping", |_| ())?;
+                trace!("mapped external {} port {}", proto, external);
+                Ok(self)
+            })
+    }
+
+    /// Look up the port mapping of the given external port.
+    ///
+    /// If `remote_host` is `None`, the mapping for any remote host is returned.
+    pub fn get_specific_port_mapping(self, proto: Protocol, port: u16, remote_host: Option<IpAddr>)
+        -> impl Future<Item=(Self, PortMappingEntry), Error=Error>
+    {
+        let req =
+            util::format_get_specific_port_mapping_entry(&self.state, proto, port, remote_host);
+        trace!("connecting to {}", self.state.addr);
+        util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| {
+                let entry = extract_soap_response(&response, "GetSpecificPortMappingEntry", |r| {
+                    extract_specific_entry(&r, proto, port, remote_host)
+                })?;
+                let entry = entry.ok_or(Error::Upnp(ErrorCode::NoSuchEntryInArray, None))?;
+                trace!("port mapping entry: {:?}", entry);
+                Ok((self, entry))
+            })
+    }
+
+    /// Remove the port mapping of the given external port.
+    ///
+    /// If `remote_host` is `None`, the mapping for any remote host is removed.
+    pub fn delete_port_mapping(self, proto: Protocol, port: u16, remote_host: Option<IpAddr>)
+        -> impl Future<Item=Self, Error=Error>
+    {
+        let req = util::format_delete_port_mapping(&self.state, proto, port, remote_host);
+        trace!("connecting to {}", self.state.addr);
+        util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| {
+                extract_soap_response(&response, "DeletePortMapping", |_| ())?;
+                trace!("deleted port mapping of {} port {}", proto, port);
+                Ok(self)
             })
     }
+
+    /// Remove all port mappings with external ports in the given range.
+    ///
+    /// If `manage` is false, only mappings created by us are removed, otherwise
+    /// all mappings we are authorised to manage. Requires IGD v2.
+    pub fn delete_port_mapping_range(self, proto: Protocol, start: u16, end: u16, manage: bool)
+        -> impl Future<Item=Self, Error=Error>
+    {
+        if self.state.version() < 2 {
+            return Either::A(future::err(Error::Unsupported("DeletePortMappingRange")))
+        }
+        let req = util::format_delete_port_mapping_range(&self.state, proto, start, end, manage);
+        trace!("connecting to {}", self.state.addr);
+        Either::B(util::fetch(self.state.addr, req, &self.config)
+            .and_then(move |response| {
+                extract_soap_response(&response, "DeletePortMappingRange", |_| ())?;
+                trace!("deleted port mappings of {} ports {} - {}", proto, start, end);
+                Ok(self)
+            }))
+    }
+
+    /// List the port mappings of the gateway.
+    ///
+    /// IGD v2 gateways are asked for their `GetListOfPortMappings`, all others
+    /// (and v2 gateways which fail to provide the list) are asked for one
+    /// `GetGenericPortMappingEntry` after another.
+    pub fn port_mappings(&self) -> impl Stream<Item=PortMappingEntry, Error=Error> {
+        let ctrl = self.state.clone();
+        let config = self.config.clone();
+        let list = if ctrl.version() >= 2 {
+            let tcp = list_port_mappings(&ctrl, Protocol::Tcp, &config);
+            let udp = list_port_mappings(&ctrl, Protocol::Udp, &config);
+            Either::A(tcp.join(udp)
+                .map(|(mut tcp, udp)| {
+                    tcp.extend(udp);
+                    Some(tcp)
+                })
+                .or_else(|e| {
+                    debug!("GetListOfPortMappings failed: {}", e);
+                    Ok(None)
+                }))
+        } else {
+            Either::B(future::ok(None))
+        };
+        list.map(move |list| {
+            if let Some(list) = list {
+                Either::A(futures::stream::iter_ok(list))
+            } else {
+                Either::B(generic_port_mappings(ctrl, config))
+            }
+        })
+        .flatten_stream()
+    }
 }
 
-fn extract_control_url(mut base: Url, description: &[u8]) -> Result<Url> {
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut response = httparse::Response::new(&mut headers);
-    match response.parse(description)? {
-        httparse::Status::Complete(n) => {
-            if Some(200) != response.code {
-                return Err(Error::StatusCode(response.code))
+/// Get all port mappings of one protocol with `GetListOfPortMappings`.
+fn list_port_mappings(ctrl: &Control, proto: Protocol, config: &Config)
+    -> impl Future<Item=Vec<PortMappingEntry>, Error=Error>
+{
+    let req = util::format_get_list_of_port_mappings(ctrl, proto);
+    trace!("connecting to {}", ctrl.addr);
+    util::fetch(ctrl.addr, req, config)
+        .then(|result| {
+            match result.and_then(|response| extract_port_mapping_list(&response)) {
+                Err(Error::Upnp(ErrorCode::PortMappingNotFound, _)) => Ok(Vec::new()),
+                other => other
             }
-            let body_string = str::from_utf8(&description[n ..])?;
-            let document = Document::parse(body_string)?;
-            for node in document.descendants().filter(|n| n.has_tag_name("service")) {
-                let cursor = xml::Cursor::new(node);
-                let service = cursor.get("serviceType");
-                if Ascii::new(SERVICE_TYPE) != service.text().unwrap_or("") {
-                    continue
-                }
-                let ctrl_url = cursor.get("controlURL");
-                if let Some(url) = ctrl_url.text() {
-                    base.set_path(url);
-                    return Ok(base)
+        })
+}
+
+/// Walk the port mappings with `GetGenericPortMappingEntry` until the gateway
+/// reports an invalid index.
+fn generic_port_mappings(ctrl: Control, config: Config)
+    -> impl Stream<Item=PortMappingEntry, Error=Error>
+{
+    futures::stream::unfold(Some(0u16), move |index| {
+        let index = index?;
+        let req = util::format_get_generic_port_mapping_entry(&ctrl, index);
+        trace!("connecting to {}", ctrl.addr);
+        Some(util::fetch(ctrl.addr, req, &config)
+            .and_then(|response| {
+                let action = "GetGenericPortMappingEntry";
+                extract_soap_response(&response, action, |r| extract_entry(&r))
+            })
+            .then(move |result| {
+                match result {
+                    Ok(entry) => {
+                        if entry.is_none() {
+                            debug!("ignoring invalid port mapping entry {}", index)
+                        }
+                        Ok((entry, index.checked_add(1)))
+                    }
+                    Err(Error::Upnp(ErrorCode::SpecifiedArrayIndexInvalid, _)) |
+                    Err(Error::Upnp(ErrorCode::NoSuchEntryInArray, _)) => Ok((None, None)),
+                    Err(e) => Err(e)
                 }
-            }
-            Err(Error::ControlUrl)
+            }))
+    })
+    .filter_map(|entry| entry)
+}
+
+/// Determine the address we use to reach `gateway` if `local` is unspecified.
+fn internal_client(local: IpAddr, gateway: SocketAddr) -> IpAddr {
+    if !local.is_unspecified() {
+        return local
+    }
+    match util::probe_local_addr(gateway) {
+        Ok(addr) => {
+            trace!("using internal client address {} for gateway {}", addr, gateway);
+            addr
         }
-        httparse::Status::Partial => {
-            unimplemented!() // TODO
+        Err(e) => {
+            debug!("failed to determine internal client address for {}: {}", gateway, e);
+            local
         }
     }
 }
 
-fn extract_external_ip(bytes: &[u8]) -> Result<Option<IpAddr>> {
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut response = httparse::Response::new(&mut headers);
-    match response.parse(bytes)? {
-        httparse::Status::Complete(n) => {
-            if Some(200) != response.code {
-                return Err(Error::StatusCode(response.code))
-            }
-            let body_string = str::from_utf8(&bytes[n ..])?;
-            let document = Document::parse(body_string)?;
-            let cursor = xml::Cursor::new(document.root());
-            let ext_ip = cursor
-                .get("Envelope")
-                .get("Body")
-                .get("GetExternalIPAddressResponse")
-                .get("NewExternalIPAddress");
-            Ok(ext_ip.text().and_then(|s| s.parse().ok()))
-        }
-        httparse::Status::Partial => {
-            unimplemented!() // TODO
+/// Get the device description at the location of a discovered gateway.
+fn fetch_description(discovery: &Discovery, config: &Config)
+    -> impl Future<Item=DeviceDescription, Error=Error>
+{
+    let req = util::format_get_req(&discovery.url);
+    let location = discovery.url.clone();
+    trace!("connecting to {}", discovery.addr);
+    util::fetch(discovery.addr, req, config)
+        .and_then(move |response| description::extract(&response, &location))
+}
+
+/// Find the control URLs and types of all `WANIPConnection` and
+/// `WANPPPConnection` services, most preferred first.
+fn extract_control_urls(description: &DeviceDescription) -> Result<Vec<(Url, String)>> {
+    let mut services = Vec::new();
+    for service in description.services() {
+        let rank = CONNECTION_SERVICES.iter()
+            .position(|t| Ascii::new(*t) == Ascii::new(service.service_type.as_str()));
+        if let (Some(rank), Some(url)) = (rank, &service.control_url) {
+            services.push((rank, url.clone(), service.service_type.clone()))
         }
     }
+    if services.is_empty() {
+        return Err(Error::ControlUrl)
+    }
+    services.sort_by_key(|(rank, _, _)| *rank);
+    Ok(services.into_iter().map(|(_, url, service)| (url, service)).collect())
 }
 
-fn extract_port_mapping(bytes: &[u8]) -> Result<Option<u16>> {
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut response = httparse::Response::new(&mut headers);
-    match response.parse(bytes)? {
-        httparse::Status::Complete(n) => {
-            if Some(200) != response.code {
-                return Err(Error::StatusCode(response.code))
-            }
-            let body_string = str::from_utf8(&bytes[n ..])?;
-            let document = Document::parse(body_string)?;
-            let cursor = xml::Cursor::new(document.root());
-            let port = cursor
-                .get("Envelope")
-                .get("Body")
-                .get("AddAnyPortMapping")
-                .get("NewReservedPort");
-            Ok(port.text().and_then(|s| s.parse().ok()))
-        }
-        httparse::Status::Partial => {
-            unimplemented!() // TODO
+/// Query the `NewConnectionStatus` of a connection service.
+fn connection_status(ctrl: &Control, config: &Config)
+    -> impl Future<Item=Option<String>, Error=Error>
+{
+    let req = util::format_soap_request(&ctrl.url, &ctrl.service, "GetStatusInfo", &[]);
+    trace!("connecting to {}", ctrl.addr);
+    util::fetch(ctrl.addr, req, config)
+        .and_then(|response| {
+            extract_soap_response(&response, "GetStatusInfo", |r| {
+                r.get("NewConnectionStatus").text().map(|s| s.trim().to_string())
+            })
+        })
+}
+
+/// Parse a SOAP response to `action` and hand its response element to `f`.
+///
+/// SOAP faults carrying a UPnP error are turned into `Error::Upnp`.
+fn extract_soap_response<F, T>(response: &http::Response, action: &str, f: F) -> Result<T>
+where
+    F: for<'a, 'd> FnOnce(xml::Cursor<'a, 'd>) -> T
+{
+    if Some(200) != response.head.code {
+        if let Some(e) = extract_soap_fault(&response.body) {
+            debug!("{} failed: {}", action, e);
+            return Err(e)
         }
+        return Err(Error::StatusCode(response.head.code))
     }
+    let body_string = str::from_utf8(&response.body)?;
+    let document = Document::parse(body_string)?;
+    let cursor = xml::Cursor::new(document.root());
+    let response = cursor
+        .get("Envelope")
+        .get("Body")
+        .get(&format!("{}Response", action));
+    Ok(f(response))
+}
+
+/// Parse the `UPnPError` of a SOAP fault, if any.
+fn extract_soap_fault(body: &[u8]) -> Option<Error> {
+    let body_string = str::from_utf8(body).ok()?;
+    let document = Document::parse(body_string).ok()?;
+    let cursor = xml::Cursor::new(document.root());
+    let error = cursor
+        .get("Envelope")
+        .get("Body")
+        .get("Fault")
+        .get("detail")
+        .get("UPnPError");
+    let code = error.get("errorCode").text().and_then(|c| c.trim().parse::<u16>().ok())?;
+    let description = error.get("errorDescription").text().map(|d| d.trim().to_string());
+    Some(Error::Upnp(ErrorCode::from(code), description))
+}
+
+/// Extract a port mapping entry from either a `GetGenericPortMappingEntry`
+/// response or a `PortMappingEntry` element of a `PortMappingList`.
+fn extract_entry(entry: &xml::Cursor) -> Option<PortMappingEntry> {
+    let text = |names: &[&str]| {
+        names.iter()
+            .filter_map(|name| entry.get(name).text().map(|t| t.trim().to_string()))
+            .next()
+    };
+    let remote_host = match text(&["NewRemoteHost"]) {
+        Some(ref host) if !host.is_empty() => Some(host.parse().ok()?),
+        _ => None
+    };
+    Some(PortMappingEntry {
+        remote_host,
+        external_port: text(&["NewExternalPort"])?.parse().ok()?,
+        protocol: Protocol::parse(&text(&["NewProtocol"])?)?,
+        internal_client: text(&["NewInternalClient"])?.parse().ok()?,
+        internal_port: text(&["NewInternalPort"])?.parse().ok()?,
+        enabled: text(&["NewEnabled"]).map(|e| e == "1" || e == "true").unwrap_or(false),
+        description: text(&["NewPortMappingDescription", "NewDescription"]).unwrap_or_default(),
+        lease_duration: text(&["NewLeaseDuration", "NewLeaseTime"])
+            .and_then(|d| d.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default()
+    })
+}
+
+/// Extract a port mapping entry from a `GetSpecificPortMappingEntry` response.
+fn extract_specific_entry(r: &xml::Cursor, proto: Protocol, port: u16, remote: Option<IpAddr>)
+    -> Option<PortMappingEntry>
+{
+    let text = |name: &str| r.get(name).text().map(|t| t.trim().to_string());
+    Some(PortMappingEntry {
+        remote_host: remote,
+        external_port: port,
+        protocol: proto,
+        internal_client: text("NewInternalClient")?.parse().ok()?,
+        internal_port: text("NewInternalPort")?.parse().ok()?,
+        enabled: text("NewEnabled").map(|e| e == "1" || e == "true").unwrap_or(false),
+        description: text("NewPortMappingDescription").unwrap_or_default(),
+        lease_duration: text("NewLeaseDuration")
+            .and_then(|d| d.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default()
+    })
+}
+
+/// Extract the entries of a `GetListOfPortMappings` response.
+fn extract_port_mapping_list(response: &http::Response) -> Result<Vec<PortMappingEntry>> {
+    let listing = extract_soap_response(response, "GetListOfPortMappings", |response| {
+        response.get("NewPortListing").text().map(|t| t.to_string())
+    })?;
+    let listing = listing.ok_or(Error::Unsupported("GetListOfPortMappings"))?;
+    let document = Document::parse(&listing)?;
+    let entries = document.descendants()
+        .filter(|n| n.has_tag_name("PortMappingEntry"))
+        .filter_map(|n| {
+            let entry = extract_entry(&xml::Cursor::new(n));
+            if entry.is_none() {
+                debug!("ignoring invalid port mapping entry")
+            }
+            entry
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn extract_external_ip(response: &http::Response) -> Result<Option<IpAddr>> {
+    extract_soap_response(response, "GetExternalIPAddress", |response| {
+        response.get("NewExternalIPAddress").text().and_then(|s| s.parse().ok())
+    })
+}
+
+fn extract_port_mapping(response: &http::Response) -> Result<Option<u16>> {
+    extract_soap_response(response, "AddAnyPortMapping", |response| {
+        response.get("NewReservedPort").text().and_then(|s| s.parse().ok())
+    })
 }
 
 #[cfg(test)]
@@ -333,6 +981,10 @@ mod tests {
     extern crate tokio;
     use super::*;
 
+    fn response(bytes: &[u8]) -> http::Response {
+        http::read_response(bytes, 64 * 1024).wait().unwrap()
+    }
+
     #[test]
     fn test_external_ip() {
         let _ = env_logger::try_init();
@@ -342,6 +994,237 @@ mod tests {
         tokio::run(f)
     }
 
+    #[test]
+    fn test_discover_all() {
+        let responder = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let igdp = IgdpBuilder::new()
+            .tries(2)
+            .wait(Duration::from_millis(300))
+            .multicast_addr(responder.local_addr().unwrap())
+            .validate_location(false)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let a = "http://127.0.0.1:5001/rootDesc.xml";
+        let b = "http://127.0.0.1:5002/rootDesc.xml";
+        let first_round = [
+            (util::IGD_1, "uuid:a::igd", a),
+            (util::IGD_1, "uuid:a::igd", a),
+            (util::WAN_IP_CONNECTION_1, "uuid:a::wanip", a)
+        ];
+        // Sent in the second round, after `discover` would have stopped.
+        let second_round = [
+            (util::IGD_1, "uuid:a::igd", a),
+            (util::WAN_IP_CONNECTION_2, "uuid:b::wanip", b)
+        ];
+        let handle = std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            let send = |from, responses: &[(&str, &str, &str)]| {
+                for (st, usn, location) in responses {
+                    let response = format!("HTTP/1.1 200 OK\r\nST: {}\r\nUSN: {}\r\n\
+                        LOCATION: {}\r\n\r\n", st, usn, location);
+                    responder.send_to(response.as_bytes(), from).unwrap();
+                }
+            };
+            let (_, from) = responder.recv_from(&mut buffer).unwrap();
+            send(from, &first_round);
+            for _ in 1 .. util::SEARCH_TARGETS.len() + 1 {
+                responder.recv_from(&mut buffer).unwrap();
+            }
+            send(from, &second_round)
+        });
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let gateways = rt.block_on(igdp.discover_all()).unwrap();
+        handle.join().unwrap();
+        let found: Vec<_> = gateways.iter()
+            .map(|igdp| {
+                let st = igdp.search_response().and_then(|r| r.st.clone()).unwrap();
+                (igdp.location().as_str(), st)
+            })
+            .collect();
+        assert_eq!(vec![
+            (b, util::WAN_IP_CONNECTION_2.to_string()),
+            (a, util::WAN_IP_CONNECTION_1.to_string())
+        ], found)
+    }
+
+    #[test]
+    fn test_discover_falls_back() {
+        let responder = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let igdp = IgdpBuilder::new()
+            .tries(1)
+            .wait(Duration::from_millis(500))
+            .multicast_addr(responder.local_addr().unwrap())
+            .validate_location(false)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let responses = [
+            (util::WAN_IP_CONNECTION_2, "x-unknown://127.0.0.1/rootDesc.xml"),
+            (util::IGD_1, "http://127.0.0.1:5000/rootDesc.xml")
+        ];
+        let handle = std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            let (_, from) = responder.recv_from(&mut buffer).unwrap();
+            for (st, location) in &responses {
+                let response = format!("HTTP/1.1 200 OK\r\nST: {}\r\nLOCATION: {}\r\n\r\n",
+                    st, location);
+                responder.send_to(response.as_bytes(), from).unwrap();
+            }
+        });
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let igdp = rt.block_on(igdp.discover()).unwrap();
+        assert_eq!("http://127.0.0.1:5000/rootDesc.xml", igdp.location().as_str());
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn test_from_known_urls() {
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let location = "http://localhost/rootDesc.xml";
+        let igdp = Igdp::from_location(unspecified, location).wait().unwrap();
+        assert!(igdp.internal_client().is_loopback());
+        assert!(igdp.state.addr.ip().is_loopback());
+        assert_eq!(80, igdp.state.addr.port());
+
+        let url = "http://127.0.0.1:5000/ctl/IPConn";
+        let igdp = Igdp::from_control(url, util::WAN_IP_CONNECTION_2).wait().unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), igdp.internal_client());
+        assert_eq!(2, igdp.state.version());
+
+        let url: Url = "http://127.0.0.1:5000/ctl/IPConn".parse().unwrap();
+        let igdp = Igdp::from_control(url, util::WAN_IP_CONNECTION_1).wait().unwrap();
+        assert_eq!(5000, igdp.state.addr.port());
+
+        match Igdp::from_control("soap://127.0.0.1/ctl", util::WAN_IP_CONNECTION_1).wait() {
+            Err(Error::Scheme(scheme)) => assert_eq!("soap", scheme),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_control_url_on_other_host() {
+        let description = "<?xml version=\"1.0\"?>\
+            <root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+              <device><serviceList><service>\
+                <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                <controlURL>http://127.0.0.2:5000/ctl/IPConn</controlURL>\
+              </service></serviceList></device>\
+            </root>";
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+        let (addr, handle) = util::tests::soap_server(description);
+        let location = format!("http://{}/rootDesc.xml", addr);
+        let igdp = rt.block_on(Igdp::from_location(unspecified, location)).unwrap();
+        match rt.block_on(igdp.control()) {
+            Err(Error::ControlUrl) => {}
+            other => panic!("unexpected result: {:?}", other.map(|igdp| igdp.state.addr))
+        }
+        handle.join().unwrap();
+
+        let (addr, handle) = util::tests::soap_server(description);
+        let location = format!("http://{}/rootDesc.xml", addr);
+        let mut igdp = rt.block_on(Igdp::from_location(unspecified, location)).unwrap();
+        igdp.set_validate_location(false);
+        let igdp = rt.block_on(igdp.control()).unwrap();
+        assert_eq!("127.0.0.2:5000".parse::<SocketAddr>().unwrap(), igdp.state.addr);
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn test_extract_control_urls() {
+        let description = b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n\
+            <?xml version=\"1.0\"?>\
+            <root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+              <device><deviceList><device><deviceList><device><serviceList>\
+                <service>\
+                  <serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+                  <controlURL>/upnp/control/WANPPPConn1</controlURL>\
+                </service>\
+                <service>\
+                  <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                  <controlURL>/upnp/control/WANIPConn1</controlURL>\
+                </service>\
+              </serviceList></device></deviceList></device></deviceList></device>\
+            </root>";
+        let base = Url::parse("http://192.168.178.1:49000/igddesc.xml").unwrap();
+        let description = description::extract(&response(&description[..]), &base).unwrap();
+        let services = extract_control_urls(&description).unwrap();
+        assert_eq!(2, services.len());
+        assert_eq!("http://192.168.178.1:49000/upnp/control/WANIPConn1", services[0].0.as_str());
+        assert_eq!("urn:schemas-upnp-org:service:WANIPConnection:1", services[0].1);
+        assert_eq!("http://192.168.178.1:49000/upnp/control/WANPPPConn1", services[1].0.as_str());
+        assert_eq!("urn:schemas-upnp-org:service:WANPPPConnection:1", services[1].1)
+    }
+
+    #[test]
+    fn test_extract_soap_fault() {
+        let fault = b"HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/xml\r\n\r\n\
+            <?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+              <s:Body><s:Fault>\
+                <faultcode>s:Client</faultcode>\
+                <faultstring>UPnPError</faultstring>\
+                <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+                  <errorCode>718</errorCode>\
+                  <errorDescription>ConflictInMappingEntry</errorDescription>\
+                </UPnPError></detail>\
+              </s:Fault></s:Body>\
+            </s:Envelope>";
+        match extract_port_mapping(&response(&fault[..])) {
+            Err(Error::Upnp(ErrorCode::ConflictInMappingEntry, Some(ref d))) => {
+                assert_eq!("ConflictInMappingEntry", d)
+            }
+            other => panic!("unexpected result: {:?}", other)
+        }
+        // Codes of other services, e.g. WANIPv6FirewallControl, are not decoded.
+        let fault = String::from_utf8(fault.to_vec()).unwrap()
+            .replace("718", "704")
+            .replace("ConflictInMappingEntry", "ConnectionSetupFailed");
+        match extract_port_mapping(&response(fault.as_bytes())) {
+            Err(Error::Upnp(ErrorCode::Other(704), _)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_extract_port_mapping_list() {
+        let listing = b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n\
+            <?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+              <s:Body>\
+                <u:GetListOfPortMappingsResponse \
+                    xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:2\">\
+                  <NewPortListing>&lt;?xml version=&quot;1.0&quot;?&gt;\
+                    &lt;p:PortMappingList xmlns:p=&quot;urn:schemas-upnp-org:gw:WANIPConnection&quot;&gt;\
+                    &lt;p:PortMappingEntry&gt;\
+                      &lt;p:NewRemoteHost&gt;&lt;/p:NewRemoteHost&gt;\
+                      &lt;p:NewExternalPort&gt;30303&lt;/p:NewExternalPort&gt;\
+                      &lt;p:NewProtocol&gt;UDP&lt;/p:NewProtocol&gt;\
+                      &lt;p:NewInternalPort&gt;30303&lt;/p:NewInternalPort&gt;\
+                      &lt;p:NewInternalClient&gt;192.168.1.10&lt;/p:NewInternalClient&gt;\
+                      &lt;p:NewEnabled&gt;1&lt;/p:NewEnabled&gt;\
+                      &lt;p:NewDescription&gt;test&lt;/p:NewDescription&gt;\
+                      &lt;p:NewLeaseTime&gt;3600&lt;/p:NewLeaseTime&gt;\
+                    &lt;/p:PortMappingEntry&gt;\
+                    &lt;/p:PortMappingList&gt;</NewPortListing>\
+                </u:GetListOfPortMappingsResponse>\
+              </s:Body>\
+            </s:Envelope>";
+        let entries = extract_port_mapping_list(&response(&listing[..])).unwrap();
+        assert_eq!(vec![PortMappingEntry {
+            remote_host: None,
+            external_port: 30303,
+            protocol: Protocol::Udp,
+            internal_client: "192.168.1.10".parse().unwrap(),
+            internal_port: 30303,
+            enabled: true,
+            description: "test".to_string(),
+            lease_duration: Duration::from_secs(3600)
+        }], entries)
+    }
+
     #[test]
     fn test_port_mapping() {
         let _ = env_logger::try_init();
@@ -0,0 +1,357 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{
+    Control,
+    DEFAULT_DEADLINE,
+    Igdp,
+    Protocol,
+    error::{Error, Step},
+    natpmp::NatPmp,
+    pcp::{MapRequest, Pcp},
+    util
+};
+use futures::{future, prelude::*};
+use log::debug;
+use std::{fmt, net::IpAddr, time::Duration};
+
+/// The description of UPnP port mappings created through `PortMapper`.
+const DESCRIPTION: &str = "upnp-igdp-crate";
+
+/// How often NAT-PMP and PCP requests are sent while probing for a gateway.
+const PROBE_TRIES: u32 = 3;
+
+/// How often requests of PCP mappers returned by `port_mapper` are sent,
+/// i.e. for about 45 seconds.
+const PCP_TRIES: u32 = 4;
+
+/// The lifetime of the PCP mapping used to learn the external address.
+const PCP_ADDRESS_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A boxed future as returned by `PortMapper`.
+pub type BoxFuture<T> = Box<dyn Future<Item=T, Error=Error> + Send>;
+
+/// The protocol a `PortMapper` speaks to the gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mechanism {
+    /// UPnP Internet Gateway Device.
+    Upnp,
+    /// Port Control Protocol (RFC 6887).
+    Pcp,
+    /// NAT Port Mapping Protocol (RFC 6886).
+    NatPmp
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mechanism::Upnp => f.write_str("UPnP IGD"),
+            Mechanism::Pcp => f.write_str("PCP"),
+            Mechanism::NatPmp => f.write_str("NAT-PMP")
+        }
+    }
+}
+
+/// A port mapping created through `PortMapper`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// The protocol of the mapping.
+    pub protocol: Protocol,
+    /// The internal port traffic is forwarded to.
+    pub internal_port: u16,
+    /// The external port traffic is received at.
+    pub external_port: u16,
+    /// How long the mapping lasts, zero if it is permanent.
+    pub lifetime: Duration
+}
+
+/// Creates port mappings regardless of the protocol the gateway speaks.
+///
+/// Mappings forward traffic to the address we use to reach the gateway.
+/// Use `port_mapper` to find out which protocol that is.
+pub trait PortMapper {
+    /// The protocol spoken to the gateway.
+    fn mechanism(&self) -> Mechanism;
+
+    /// Get the external address of the gateway.
+    fn external_address(&self) -> BoxFuture<IpAddr>;
+
+    /// Create or renew a mapping of the given internal port for `lifetime`.
+    ///
+    /// The same port number is requested externally, but the gateway may
+    /// choose a different one.
+    fn map(&self, proto: Protocol, port: u16, lifetime: Duration) -> BoxFuture<Mapping>;
+
+    /// Remove a mapping created with `map`.
+    fn unmap(&self, mapping: &Mapping) -> BoxFuture<()>;
+}
+
+impl PortMapper for Igdp<Control> {
+    fn mechanism(&self) -> Mechanism {
+        Mechanism::Upnp
+    }
+
+    fn external_address(&self) -> BoxFuture<IpAddr> {
+        Box::new(self.clone().external_ip().and_then(|(_, addr)| {
+            addr.ok_or(Error::Unsupported("GetExternalIPAddress"))
+        }))
+    }
+
+    fn map(&self, proto: Protocol, port: u16, lifetime: Duration) -> BoxFuture<Mapping> {
+        Box::new(self.clone().add_port_mapping(proto, port, lifetime, DESCRIPTION)
+            .map(move |(_, external)| {
+                let external_port = external.unwrap_or_else(|| {
+                    debug!("gateway did not report external port, assuming {}", port);
+                    port
+                });
+                Mapping { protocol: proto, internal_port: port, external_port, lifetime }
+            }))
+    }
+
+    fn unmap(&self, mapping: &Mapping) -> BoxFuture<()> {
+        let f = self.clone().delete_port_mapping(mapping.protocol, mapping.external_port, None);
+        Box::new(f.map(|_| ()))
+    }
+}
+
+impl PortMapper for NatPmp {
+    fn mechanism(&self) -> Mechanism {
+        Mechanism::NatPmp
+    }
+
+    fn external_address(&self) -> BoxFuture<IpAddr> {
+        Box::new(self.external_ip().map(IpAddr::V4))
+    }
+
+    fn map(&self, proto: Protocol, port: u16, lifetime: Duration) -> BoxFuture<Mapping> {
+        Box::new(self.add_port_mapping(proto, port, lifetime).map(|mapping| {
+            Mapping {
+                protocol: mapping.protocol,
+                internal_port: mapping.internal_port,
+                external_port: mapping.external_port,
+                lifetime: mapping.lifetime
+            }
+        }))
+    }
+
+    fn unmap(&self, mapping: &Mapping) -> BoxFuture<()> {
+        Box::new(self.delete_port_mapping(mapping.protocol, mapping.internal_port))
+    }
+}
+
+/// PCP mappings are renewed and removed with the nonce they were created
+/// with, which is remembered by the `Pcp` client and its clones.
+impl PortMapper for Pcp {
+    fn mechanism(&self) -> Mechanism {
+        Mechanism::Pcp
+    }
+
+    /// PCP lacks a request for the external address, so a short-lived UDP
+    /// mapping of an unused port is created and removed again.
+    fn external_address(&self) -> BoxFuture<IpAddr> {
+        let socket = match std::net::UdpSocket::bind((self.client(), 0)) {
+            Ok(socket) => socket,
+            Err(e) => return Box::new(future::err(e.into()))
+        };
+        let port = match socket.local_addr() {
+            Ok(addr) => addr.port(),
+            Err(e) => return Box::new(future::err(e.into()))
+        };
+        let request = MapRequest::new(Protocol::Udp, port, PCP_ADDRESS_LIFETIME);
+        let pcp = self.clone();
+        Box::new(Pcp::map(self, &request).and_then(move |mapping| {
+            let delete = MapRequest { lifetime: Duration::from_secs(0), .. request };
+            Pcp::map(&pcp, &delete).then(move |result| {
+                if let Err(e) = result {
+                    debug!("failed to delete pcp mapping of udp port {}: {}", port, e)
+                }
+                drop(socket);
+                Ok(mapping.external.ip())
+            })
+        }))
+    }
+
+    fn map(&self, proto: Protocol, port: u16, lifetime: Duration) -> BoxFuture<Mapping> {
+        let request = remembered(self, proto, port, lifetime);
+        let nonces = self.nonces.clone();
+        Box::new(Pcp::map(self, &request).map(move |mapping| {
+            nonces.lock().expect("not poisoned").insert((proto, port), mapping.nonce);
+            Mapping {
+                protocol: proto,
+                internal_port: port,
+                external_port: mapping.external.port(),
+                lifetime: mapping.lifetime
+            }
+        }))
+    }
+
+    fn unmap(&self, mapping: &Mapping) -> BoxFuture<()> {
+        let key = (mapping.protocol, mapping.internal_port);
+        let request = remembered(self, key.0, key.1, Duration::from_secs(0));
+        let nonces = self.nonces.clone();
+        Box::new(Pcp::map(self, &request).map(move |_| {
+            nonces.lock().expect("not poisoned").remove(&key);
+        }))
+    }
+}
+
+/// A MAP request with the nonce of an earlier mapping of the port, if any.
+fn remembered(pcp: &Pcp, proto: Protocol, port: u16, lifetime: Duration) -> MapRequest {
+    let mut request = MapRequest::new(proto, port, lifetime);
+    if let Some(nonce) = pcp.nonces.lock().expect("not poisoned").get(&(proto, port)) {
+        request.nonce = *nonce
+    }
+    request
+}
+
+/// Find a gateway which speaks UPnP IGD, PCP or NAT-PMP.
+///
+/// All three are probed in parallel and the first gateway to answer is used.
+/// `PortMapper::mechanism` reports which one that is. Requests of the mapper
+/// are retransmitted as their protocol specifies, except that PCP requests,
+/// which RFC 6887 retransmits until the gateway answers, give up after about
+/// 45 seconds.
+pub fn port_mapper() -> impl Future<Item=Box<dyn PortMapper + Send>, Error=Error> {
+    port_mapper_within(DEFAULT_DEADLINE)
+}
+
+/// Like `port_mapper` but fails with `Error::Timeout(Step::Deadline)` after `deadline`.
+pub fn port_mapper_within(deadline: Duration)
+    -> impl Future<Item=Box<dyn PortMapper + Send>, Error=Error>
+{
+    let mut probes: Vec<BoxFuture<Box<dyn PortMapper + Send>>> = Vec::new();
+    probes.push(Box::new(future::result(Igdp::bind("0.0.0.0:0"))
+        .and_then(Igdp::discover)
+        .and_then(Igdp::control)
+        .map(|igdp| Box::new(igdp) as Box<dyn PortMapper + Send>)));
+    match Pcp::default_gateway() {
+        Ok(pcp) => probes.push(probe_pcp(pcp)),
+        Err(e) => debug!("not probing for pcp: {}", e)
+    }
+    match NatPmp::default_gateway() {
+        Ok(natpmp) => probes.push(probe_natpmp(natpmp)),
+        Err(e) => debug!("not probing for nat-pmp: {}", e)
+    }
+    select(probes, deadline)
+}
+
+/// Check that `pcp` answers, returning it with bounded retransmissions.
+fn probe_pcp(mut pcp: Pcp) -> BoxFuture<Box<dyn PortMapper + Send>> {
+    let mut probe = pcp.clone();
+    probe.set_tries(PROBE_TRIES);
+    pcp.set_tries(PCP_TRIES);
+    Box::new(probe.announce().map(move |()| Box::new(pcp) as Box<dyn PortMapper + Send>))
+}
+
+/// Check that `natpmp` answers.
+fn probe_natpmp(natpmp: NatPmp) -> BoxFuture<Box<dyn PortMapper + Send>> {
+    let mut probe = natpmp.clone();
+    probe.set_tries(PROBE_TRIES);
+    Box::new(probe.external_ip().map(move |_| Box::new(natpmp) as Box<dyn PortMapper + Send>))
+}
+
+/// The mapper of the first probe to succeed within `deadline`.
+fn select(probes: Vec<BoxFuture<Box<dyn PortMapper + Send>>>, deadline: Duration)
+    -> impl Future<Item=Box<dyn PortMapper + Send>, Error=Error>
+{
+    let f = future::select_ok(probes).map(|(mapper, _)| {
+        debug!("using {} gateway", mapper.mechanism());
+        mapper
+    });
+    util::timeout(f, deadline, Step::Deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PcpCode;
+    use std::{net::SocketAddr, thread};
+
+    /// A NAT-PMP gateway answering PCP requests with UNSUPP_VERSION until
+    /// no requests arrive for a second.
+    fn natpmp_gateway() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1100];
+            while let Ok((n, from)) = socket.recv_from(&mut buffer) {
+                let request = &buffer[.. n];
+                let mut response = vec![0, 0x80 | request[1], 0, 0, 0, 0, 0, 1];
+                match request[.. 2] {
+                    [0, 0] => response.extend_from_slice(&[203, 0, 113, 1]),
+                    [0, _] => {
+                        let port = u16::from_be_bytes([request[4], request[5]]);
+                        let external = if request[8 .. 12] == [0; 4] { 0 } else { port + 1 };
+                        response.extend_from_slice(&request[4 .. 6]);
+                        response.extend_from_slice(&external.to_be_bytes());
+                        response.extend_from_slice(&request[8 .. 12])
+                    }
+                    _ => response[3] = 1
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn natpmp_when_pcp_unsupported() {
+        let addr = natpmp_gateway();
+        let pcp = Pcp::with_server(addr, "127.0.0.1".parse().unwrap(), 1);
+        let natpmp = NatPmp::with_gateway(addr, 1);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        // PCP retransmits after 3s, so failing before means the response was understood.
+        let deadline = Duration::from_secs(2);
+        match rt.block_on(select(vec![probe_pcp(pcp.clone())], deadline)) {
+            Err(Error::Pcp(PcpCode::UnsupportedVersion)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|m| m.mechanism()))
+        }
+        let probes = vec![probe_pcp(pcp), probe_natpmp(natpmp)];
+        let mapper = rt.block_on(select(probes, deadline)).unwrap();
+        assert_eq!(Mechanism::NatPmp, mapper.mechanism());
+        let addr: IpAddr = "203.0.113.1".parse().unwrap();
+        assert_eq!(addr, rt.block_on(mapper.external_address()).unwrap())
+    }
+
+    #[test]
+    fn natpmp_mapper() {
+        let natpmp = NatPmp::with_gateway(natpmp_gateway(), 1);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let lifetime = Duration::from_secs(7200);
+        let mapping = rt.block_on(PortMapper::map(&natpmp, Protocol::Udp, 30303, lifetime));
+        let mapping = mapping.unwrap();
+        assert_eq!(Mapping {
+            protocol: Protocol::Udp,
+            internal_port: 30303,
+            external_port: 30304,
+            lifetime
+        }, mapping);
+        rt.block_on(natpmp.unmap(&mapping)).unwrap()
+    }
+
+    #[test]
+    fn upnp_without_external_address() {
+        let (addr, handle) = util::tests::soap_server("<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+            <u:GetExternalIPAddressResponse \
+            xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:2\">\
+            <NewExternalIPAddress></NewExternalIPAddress>\
+            </u:GetExternalIPAddressResponse></s:Body></s:Envelope>");
+        let igdp = util::tests::igdp_at(addr);
+        assert_eq!(Mechanism::Upnp, igdp.mechanism());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        match rt.block_on(igdp.external_address()) {
+            Err(Error::Unsupported("GetExternalIPAddress")) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        handle.join().unwrap()
+    }
+}
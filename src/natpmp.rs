@@ -0,0 +1,239 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! A NAT Port Mapping Protocol client (RFC 6886).
+
+use crate::{Protocol, error::{Error, NatPmpCode, Result}, util};
+use futures::prelude::*;
+use log::trace;
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, time::Duration};
+
+/// The port NAT-PMP gateways listen on.
+const PORT: u16 = 5351;
+
+/// How long to wait for the first response, doubled with every retransmission.
+const INITIAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often requests are sent by default, i.e. for about two minutes.
+const TRIES: u32 = 9;
+
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+
+/// A port mapping created or removed by a NAT-PMP gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NatPmpMapping {
+    /// The protocol of the mapping.
+    pub protocol: Protocol,
+    /// The internal port traffic is forwarded to.
+    pub internal_port: u16,
+    /// The external port the gateway assigned, zero if the mapping was removed.
+    pub external_port: u16,
+    /// The lifetime the gateway granted, zero if the mapping was removed.
+    pub lifetime: Duration
+}
+
+/// A client of a NAT-PMP gateway.
+///
+/// Many gateways support NAT-PMP with UPnP disabled, so it is worth trying
+/// when `Igdp::discover` fails with `Error::Timeout(Step::Discovery)`.
+///
+/// Requests are sent over UDP and retransmitted after 250ms, doubling the
+/// interval every time, until the gateway responds or all tries are used up,
+/// which results in `Error::Timeout(Step::Receive)`.
+#[derive(Clone, Debug)]
+pub struct NatPmp {
+    gateway: SocketAddr,
+    tries: u32
+}
+
+impl NatPmp {
+    /// Create a client of the gateway with the given address.
+    pub fn new(gateway: Ipv4Addr) -> Self {
+        NatPmp { gateway: SocketAddr::new(IpAddr::V4(gateway), PORT), tries: TRIES }
+    }
+
+    /// Create a client of a gateway listening on a port other than 5351.
+    #[cfg(test)]
+    pub(crate) fn with_gateway(gateway: SocketAddr, tries: u32) -> Self {
+        NatPmp { gateway, tries }
+    }
+
+    /// Create a client of the default gateway.
+    ///
+    /// Looking up the default gateway is only supported on Linux.
+    pub fn default_gateway() -> Result<Self> {
+        let gateway = util::default_gateway()?;
+        trace!("default gateway: {}", gateway);
+        Ok(NatPmp::new(gateway))
+    }
+
+    /// The address of the gateway.
+    pub fn gateway(&self) -> SocketAddr {
+        self.gateway
+    }
+
+    /// Set how often a request is sent before giving up. The default is 9,
+    /// as recommended by RFC 6886, which means waiting for about two minutes.
+    ///
+    /// Requests are always sent at least once.
+    pub fn set_tries(&mut self, tries: u32) {
+        self.tries = tries
+    }
+
+    /// Get the external IPv4 address of the gateway.
+    pub fn external_ip(&self) -> impl Future<Item=Ipv4Addr, Error=Error> {
+        self.request(OP_EXTERNAL_ADDRESS, vec![0, OP_EXTERNAL_ADDRESS], |payload| {
+            if payload.len() < 4 {
+                return None
+            }
+            Some(Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]))
+        })
+        .map(|addr| {
+            trace!("external IP address: {}", addr);
+            addr
+        })
+    }
+
+    /// Map an external port to the given internal port for `lifetime`.
+    ///
+    /// The same port number is requested externally, but the gateway may
+    /// choose a different one. Mappings need to be renewed by requesting them
+    /// again before their lifetime ends; RFC 6886 recommends two hours.
+    pub fn add_port_mapping(&self, proto: Protocol, port: u16, lifetime: Duration)
+        -> impl Future<Item=NatPmpMapping, Error=Error>
+    {
+        self.map(proto, port, port, lifetime)
+            .map(|mapping| {
+                trace!("mapped external {} port {}", mapping.protocol, mapping.external_port);
+                mapping
+            })
+    }
+
+    /// Remove the mapping of the given internal port.
+    pub fn delete_port_mapping(&self, proto: Protocol, port: u16)
+        -> impl Future<Item=(), Error=Error>
+    {
+        self.map(proto, port, 0, Duration::from_secs(0))
+            .map(move |_| trace!("deleted port mapping of {} port {}", proto, port))
+    }
+
+    fn map(&self, proto: Protocol, port: u16, external: u16, lifetime: Duration)
+        -> impl Future<Item=NatPmpMapping, Error=Error>
+    {
+        let opcode = match proto {
+            Protocol::Udp => OP_MAP_UDP,
+            Protocol::Tcp => OP_MAP_TCP
+        };
+        let lifetime = std::cmp::min(lifetime.as_secs(), u64::from(u32::MAX)) as u32;
+        let mut request = vec![0, opcode, 0, 0];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&external.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        self.request(opcode, request, move |payload| {
+            if payload.len() < 8 || u16::from_be_bytes([payload[0], payload[1]]) != port {
+                return None
+            }
+            let lifetime = [payload[4], payload[5], payload[6], payload[7]];
+            Some(NatPmpMapping {
+                protocol: proto,
+                internal_port: port,
+                external_port: u16::from_be_bytes([payload[2], payload[3]]),
+                lifetime: Duration::from_secs(u64::from(u32::from_be_bytes(lifetime)))
+            })
+        })
+    }
+
+    /// Send `request` and parse the payload of the response with `f`.
+    ///
+    /// Datagrams for which `f` returns `None` are ignored.
+    fn request<F, T>(&self, opcode: u8, request: Vec<u8>, f: F)
+        -> impl Future<Item=T, Error=Error>
+    where
+        F: Fn(&[u8]) -> Option<T>
+    {
+        let retransmission = util::Retransmission {
+            interval: INITIAL_INTERVAL,
+            backoff: |interval| interval * 2,
+            tries: Some(std::cmp::max(self.tries, 1))
+        };
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        util::udp_request(self.gateway, unspecified, request, retransmission, move |bytes| {
+            let payload = parse_response(bytes, opcode).transpose()?;
+            payload.map(&f).transpose()
+        })
+    }
+}
+
+/// Check the header of a response to a request with the given opcode.
+///
+/// Returns the payload following the seconds since the gateway's epoch, or
+/// `None` if the datagram is not a response to the request.
+fn parse_response(bytes: &[u8], opcode: u8) -> Result<Option<&[u8]>> {
+    if bytes.len() < 8 || bytes[0] != 0 || bytes[1] != 128 + opcode {
+        return Ok(None)
+    }
+    match u16::from_be_bytes([bytes[2], bytes[3]]) {
+        0 => Ok(Some(&bytes[8 ..])),
+        code => Err(Error::NatPmp(NatPmpCode::from(code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Answer the second request received with `response`.
+    fn gateway(response: Vec<u8>) -> (NatPmp, thread::JoinHandle<Vec<u8>>) {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let natpmp = NatPmp::with_gateway(socket.local_addr().unwrap(), 3);
+        let handle = thread::spawn(move || {
+            let mut buffer = [0; 16];
+            let _ = socket.recv_from(&mut buffer).unwrap();
+            let (n, from) = socket.recv_from(&mut buffer).unwrap();
+            socket.send_to(&response, from).unwrap();
+            buffer[.. n].to_vec()
+        });
+        (natpmp, handle)
+    }
+
+    #[test]
+    fn map_port() {
+        let response = vec![0, 130, 0, 0, 0, 0, 0, 1, 0x76, 0x5f, 0x76, 0x60, 0, 0, 0x1c, 0x20];
+        let (natpmp, handle) = gateway(response);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mapping = natpmp.add_port_mapping(Protocol::Tcp, 30303, Duration::from_secs(7200));
+        let mapping = rt.block_on(mapping).unwrap();
+        assert_eq!(NatPmpMapping {
+            protocol: Protocol::Tcp,
+            internal_port: 30303,
+            external_port: 30304,
+            lifetime: Duration::from_secs(7200)
+        }, mapping);
+        let request = handle.join().unwrap();
+        assert_eq!(vec![0, 2, 0, 0, 0x76, 0x5f, 0x76, 0x5f, 0, 0, 0x1c, 0x20], request)
+    }
+
+    #[test]
+    fn result_codes() {
+        let (natpmp, handle) = gateway(vec![0, 128, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0]);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        match rt.block_on(natpmp.external_ip()) {
+            Err(Error::NatPmp(NatPmpCode::NetworkFailure)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert_eq!(vec![0, 0], handle.join().unwrap());
+        let response = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+        assert_eq!(Some(&[203, 0, 113, 1][..]), parse_response(&response, 0).unwrap());
+        assert_eq!(None, parse_response(&response, OP_MAP_UDP).unwrap())
+    }
+}
@@ -0,0 +1,323 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{error::{Error, Result}, http, ssdp::{self, SearchResponse}};
+use futures::{prelude::*, try_ready};
+use log::{debug, trace};
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+use tokio_timer::Delay;
+use tokio_udp::UdpSocket;
+use unicase::Ascii;
+
+/// How long an announcement is valid if it lacks a `max-age`.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(1800);
+
+/// A change in the set of gateways announced on the network.
+///
+/// Gateways are told apart by the device UUID of their USN, so a gateway
+/// announcing several search targets causes one event per change. The `st`
+/// field of the `SearchResponse` holds the `NT` header of its announcement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GatewayEvent {
+    /// A gateway announced itself for the first time.
+    Appeared(SearchResponse),
+    /// A known gateway announced a new location, boot ID or configuration.
+    Updated(SearchResponse),
+    /// A known gateway said goodbye or its last announcement expired.
+    Disappeared(SearchResponse)
+}
+
+/// A parsed `NOTIFY` announcement.
+#[derive(Debug)]
+enum Notify {
+    Alive(SearchResponse),
+    Update(SearchResponse),
+    ByeBye { nt: String, usn: String }
+}
+
+/// Parse a single `NOTIFY` datagram.
+///
+/// Returns `None` for other requests and unknown or incomplete announcements.
+fn parse_notify(bytes: &[u8]) -> Result<Option<Notify>> {
+    let (method, head) = http::parse_request_head(bytes)?.ok_or(Error::Incomplete)?;
+    if Ascii::new(method.as_str()) != "NOTIFY" {
+        return Ok(None)
+    }
+    let notify = match head.header("NTS") {
+        Some(nts) if Ascii::new(nts) == "ssdp:alive" => {
+            Notify::Alive(ssdp::search_response(&head, "NT")?)
+        }
+        Some(nts) if Ascii::new(nts) == "ssdp:update" => {
+            Notify::Update(ssdp::search_response(&head, "NT")?)
+        }
+        Some(nts) if Ascii::new(nts) == "ssdp:byebye" => {
+            match (head.header("NT"), head.header("USN")) {
+                (Some(nt), Some(usn)) => Notify::ByeBye { nt: nt.to_string(), usn: usn.to_string() },
+                _ => return Ok(None)
+            }
+        }
+        _ => return Ok(None)
+    };
+    Ok(Some(notify))
+}
+
+/// The gateways we know of and when their announcements expire.
+///
+/// Gateways announce themselves once for every device and service type, so
+/// they are keyed by the device UUID of their USN, see `device`.
+#[derive(Debug)]
+struct Gateways {
+    targets: Vec<String>,
+    validate_location: bool,
+    known: HashMap<String, (SearchResponse, Instant)>
+}
+
+/// The device UUID of a USN, e.g. `uuid:1234` of `uuid:1234::upnp:rootdevice`.
+fn device(usn: &str) -> &str {
+    usn.split("::").next().unwrap_or(usn)
+}
+
+impl Gateways {
+    fn is_target(&self, nt: &str) -> bool {
+        self.targets.iter().any(|t| Ascii::new(t.as_str()) == Ascii::new(nt))
+    }
+
+    fn notify(&mut self, from: SocketAddr, notify: Notify, now: Instant) -> Option<GatewayEvent> {
+        let (response, update) = match notify {
+            Notify::Alive(response) => (response, false),
+            Notify::Update(response) => (response, true),
+            Notify::ByeBye { nt, usn } => {
+                if !self.is_target(&nt) {
+                    return None
+                }
+                trace!("{} from {} says goodbye", usn, from);
+                let (response, _) = self.known.remove(device(&usn))?;
+                return Some(GatewayEvent::Disappeared(response))
+            }
+        };
+        if !response.st.as_ref().map(|nt| self.is_target(nt)).unwrap_or(false) {
+            return None
+        }
+        if self.validate_location && !ssdp::is_valid_location(&response.location, &from) {
+            debug!("ignoring announcement from {} with location {}", from, response.location);
+            return None
+        }
+        let mut expires = now + response.max_age.unwrap_or(DEFAULT_MAX_AGE);
+        let key = match &response.usn {
+            Some(usn) => device(usn).to_string(),
+            None => response.location.to_string()
+        };
+        let event = match self.known.get(&key) {
+            None => GatewayEvent::Appeared(response.clone()),
+            Some((known, known_expires)) => {
+                let changed = update
+                    || known.location != response.location
+                    || known.boot_id != response.boot_id
+                    || known.config_id != response.config_id;
+                if changed {
+                    GatewayEvent::Updated(response.clone())
+                } else {
+                    // Another search target of the gateway may last longer.
+                    expires = std::cmp::max(expires, *known_expires);
+                    self.known.insert(key, (response, expires));
+                    return None
+                }
+            }
+        };
+        self.known.insert(key, (response, expires));
+        Some(event)
+    }
+
+    /// Remove one gateway whose announcement expired at `now`.
+    fn expire(&mut self, now: Instant) -> Option<GatewayEvent> {
+        let key = self.known.iter()
+            .find(|(_, (_, expires))| *expires <= now)
+            .map(|(key, _)| key.clone())?;
+        let (response, _) = self.known.remove(&key)?;
+        trace!("announcement of {} expired", key);
+        Some(GatewayEvent::Disappeared(response))
+    }
+
+    /// When the next announcement expires.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.known.values().map(|(_, expires)| *expires).min()
+    }
+}
+
+/// A stream of gateway events, derived from SSDP `NOTIFY` announcements.
+///
+/// Only announcements for the search targets configured with `IgdpBuilder`
+/// are considered and, unless disabled there, their location is validated
+/// like M-SEARCH responses. Create with `IgdpBuilder::listen` or
+/// `IgdpBuilder::listen_v6`.
+#[derive(Debug)]
+pub struct Announcements {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+    gateways: Gateways,
+    delay: Delay
+}
+
+impl Announcements {
+    pub(crate) fn new(socket: UdpSocket, buffer: Vec<u8>, search: &ssdp::SearchConfig) -> Self {
+        Announcements {
+            socket,
+            buffer,
+            gateways: Gateways {
+                targets: search.targets.clone(),
+                validate_location: search.validate_location,
+                known: HashMap::new()
+            },
+            delay: Delay::new(Instant::now())
+        }
+    }
+}
+
+impl Stream for Announcements {
+    type Item = GatewayEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Async::Ready((n, from)) = self.socket.poll_recv_from(&mut self.buffer)? {
+                match parse_notify(&self.buffer[.. n]) {
+                    Ok(Some(notify)) => {
+                        if let Some(event) = self.gateways.notify(from, notify, Instant::now()) {
+                            return Ok(Async::Ready(Some(event)))
+                        }
+                    }
+                    Ok(None) => trace!("ignoring datagram from {}", from),
+                    Err(e) => debug!("ignoring invalid announcement from {}: {}", from, e)
+                }
+                continue
+            }
+            if let Some(event) = self.gateways.expire(Instant::now()) {
+                return Ok(Async::Ready(Some(event)))
+            }
+            match self.gateways.next_expiry() {
+                Some(expires) => {
+                    self.delay.reset(expires);
+                    try_ready!(self.delay.poll().map_err(|_| Error::Timer))
+                }
+                None => return Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alive(location: &str, boot_id: u32) -> Vec<u8> {
+        format!("NOTIFY * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            CACHE-CONTROL: max-age=120\r\n\
+            LOCATION: {}\r\n\
+            NT: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            NTS: ssdp:alive\r\n\
+            SERVER: Linux/3.14 UPnP/1.1 MiniUPnPd/2.1\r\n\
+            USN: uuid:1234::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            BOOTID.UPNP.ORG: {}\r\n\r\n", location, boot_id).into_bytes()
+    }
+
+    const BYEBYE: &[u8] = b"NOTIFY * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1900\r\n\
+        NT: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+        NTS: ssdp:byebye\r\n\
+        USN: uuid:1234::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+
+    fn gateways() -> Gateways {
+        let search = ssdp::SearchConfig::default();
+        Gateways { targets: search.targets, validate_location: true, known: HashMap::new() }
+    }
+
+    #[test]
+    fn track_announcements() {
+        let mut gateways = gateways();
+        let from = "192.168.1.1:1900".parse().unwrap();
+        let now = Instant::now();
+        let mut notify = |bytes: &[u8], now| {
+            gateways.notify(from, parse_notify(bytes).unwrap().unwrap(), now)
+        };
+        match notify(&alive("http://192.168.1.1:5000/rootDesc.xml", 1), now) {
+            Some(GatewayEvent::Appeared(r)) => {
+                assert_eq!("http://192.168.1.1:5000/rootDesc.xml", r.location.as_str());
+                assert_eq!(Some(Duration::from_secs(120)), r.max_age)
+            }
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_eq!(None, notify(&alive("http://192.168.1.1:5000/rootDesc.xml", 1), now));
+        match notify(&alive("http://192.168.1.1:5000/rootDesc.xml", 2), now) {
+            Some(GatewayEvent::Updated(r)) => assert_eq!(Some(2), r.boot_id),
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_eq!(None, notify(&alive("http://203.0.113.1:5000/rootDesc.xml", 3), now));
+        match notify(BYEBYE, now) {
+            Some(GatewayEvent::Disappeared(r)) => assert_eq!(Some(2), r.boot_id),
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_eq!(None, notify(BYEBYE, now))
+    }
+
+    #[test]
+    fn one_event_per_gateway() {
+        let mut gateways = gateways();
+        let from = "192.168.1.1:1900".parse().unwrap();
+        let now = Instant::now();
+        let igd = alive("http://192.168.1.1:5000/rootDesc.xml", 1);
+        let wan = String::from_utf8(igd.clone()).unwrap()
+            .replace("device:InternetGatewayDevice:1", "service:WANIPConnection:1");
+        let mut notify = |bytes: &[u8]| {
+            gateways.notify(from, parse_notify(bytes).unwrap().unwrap(), now)
+        };
+        match notify(&igd) {
+            Some(GatewayEvent::Appeared(_)) => {}
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_eq!(None, notify(wan.as_bytes()));
+        let wan_byebye = String::from_utf8(BYEBYE.to_vec()).unwrap()
+            .replace("device:InternetGatewayDevice:1", "service:WANIPConnection:1");
+        match notify(wan_byebye.as_bytes()) {
+            Some(GatewayEvent::Disappeared(_)) => {}
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_eq!(None, notify(BYEBYE))
+    }
+
+    #[test]
+    fn expire_announcements() {
+        let mut gateways = gateways();
+        let from = "192.168.1.1:1900".parse().unwrap();
+        let now = Instant::now();
+        let bytes = alive("http://192.168.1.1:5000/rootDesc.xml", 1);
+        let notify = parse_notify(&bytes).unwrap().unwrap();
+        assert!(gateways.notify(from, notify, now).is_some());
+        assert_eq!(Some(now + Duration::from_secs(120)), gateways.next_expiry());
+        assert_eq!(None, gateways.expire(now + Duration::from_secs(119)));
+        match gateways.expire(now + Duration::from_secs(120)) {
+            Some(GatewayEvent::Disappeared(_)) => {}
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_eq!(None, gateways.next_expiry())
+    }
+
+    #[test]
+    fn ignore_other_announcements() {
+        let mut gateways = gateways();
+        let from = "192.168.1.1:1900".parse().unwrap();
+        let bytes = String::from_utf8(alive("http://192.168.1.1:5000/rootDesc.xml", 1)).unwrap()
+            .replace("device:InternetGatewayDevice:1", "device:MediaRenderer:1");
+        let notify = parse_notify(bytes.as_bytes()).unwrap().unwrap();
+        assert_eq!(None, gateways.notify(from, notify, Instant::now()));
+        let search = b"M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: ssdp:all\r\n\r\n";
+        assert!(parse_notify(&search[..]).unwrap().is_none())
+    }
+}
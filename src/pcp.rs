@@ -0,0 +1,568 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! A Port Control Protocol client (RFC 6887).
+
+use crate::{Protocol, error::{Error, PcpCode, Result}, util};
+use futures::prelude::*;
+use log::trace;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration
+};
+
+/// The port PCP servers listen on.
+const PORT: u16 = 5351;
+
+const VERSION: u8 = 2;
+
+const OP_ANNOUNCE: u8 = 0;
+const OP_MAP: u8 = 1;
+const OP_PEER: u8 = 2;
+
+const OPTION_THIRD_PARTY: u8 = 1;
+const OPTION_PREFER_FAILURE: u8 = 2;
+const OPTION_FILTER: u8 = 3;
+
+/// The size of the common request and response header.
+const HEADER_SIZE: usize = 24;
+
+/// The size of the MAP payload, which starts the PEER payload.
+const MAP_SIZE: usize = 36;
+
+/// Initial retransmission time.
+const IRT: Duration = Duration::from_secs(3);
+
+/// Maximum retransmission time.
+const MRT: Duration = Duration::from_secs(1024);
+
+/// Identifies a mapping towards the PCP server.
+///
+/// Refreshing or deleting a mapping requires the nonce it was created with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Nonce([u8; 12]);
+
+impl Nonce {
+    /// A new random nonce.
+    pub fn random() -> Self {
+        Nonce(rand::random())
+    }
+
+    /// A nonce with the given value, e.g. to refresh a mapping created by
+    /// an earlier process.
+    pub fn from_bytes(bytes: [u8; 12]) -> Self {
+        Nonce(bytes)
+    }
+
+    /// The value of the nonce.
+    pub fn as_bytes(&self) -> &[u8; 12] {
+        &self.0
+    }
+}
+
+/// Restricts the remote peers which may send traffic through a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Filter {
+    /// The address prefix of permitted remote peers.
+    pub remote_ip: IpAddr,
+    /// The length of the prefix, up to 32 for IPv4 and 128 for IPv6 addresses.
+    pub prefix_length: u8,
+    /// The port of permitted remote peers, any port if `None`.
+    pub remote_port: Option<u16>
+}
+
+/// A request to create, refresh or delete a mapping with the MAP opcode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapRequest {
+    /// Identifies the mapping.
+    pub nonce: Nonce,
+    /// The protocol of the mapping.
+    pub protocol: Protocol,
+    /// The internal port traffic is forwarded to.
+    pub internal_port: u16,
+    /// The external port to suggest to the server.
+    pub external_port: Option<u16>,
+    /// The external address to suggest to the server.
+    pub external_ip: Option<IpAddr>,
+    /// The requested lifetime. Zero deletes the mapping.
+    pub lifetime: Duration,
+    /// Request the mapping for another internal host (`THIRD_PARTY` option).
+    pub third_party: Option<IpAddr>,
+    /// Fail instead of mapping a different external port or address than
+    /// suggested (`PREFER_FAILURE` option).
+    pub prefer_failure: bool,
+    /// Only let traffic of matching remote peers through (`FILTER` options).
+    pub filters: Vec<Filter>
+}
+
+impl MapRequest {
+    /// Request a mapping with a new nonce, suggesting the same external port.
+    pub fn new(protocol: Protocol, internal_port: u16, lifetime: Duration) -> Self {
+        MapRequest {
+            nonce: Nonce::random(),
+            protocol,
+            internal_port,
+            external_port: Some(internal_port),
+            external_ip: None,
+            lifetime,
+            third_party: None,
+            prefer_failure: false,
+            filters: Vec::new()
+        }
+    }
+}
+
+/// A request to create or refresh a mapping for traffic with a single remote
+/// peer with the PEER opcode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerRequest {
+    /// Identifies the mapping.
+    pub nonce: Nonce,
+    /// The protocol of the mapping.
+    pub protocol: Protocol,
+    /// The internal port of the traffic.
+    pub internal_port: u16,
+    /// The external port to suggest to the server.
+    pub external_port: Option<u16>,
+    /// The external address to suggest to the server.
+    pub external_ip: Option<IpAddr>,
+    /// The address and port of the remote peer.
+    pub remote: SocketAddr,
+    /// The requested lifetime.
+    pub lifetime: Duration,
+    /// Request the mapping for another internal host (`THIRD_PARTY` option).
+    pub third_party: Option<IpAddr>
+}
+
+impl PeerRequest {
+    /// Request a mapping with a new nonce, suggesting the same external port.
+    pub fn new(protocol: Protocol, internal_port: u16, remote: SocketAddr, lifetime: Duration)
+        -> Self
+    {
+        PeerRequest {
+            nonce: Nonce::random(),
+            protocol,
+            internal_port,
+            external_port: Some(internal_port),
+            external_ip: None,
+            remote,
+            lifetime,
+            third_party: None
+        }
+    }
+}
+
+/// A mapping created, refreshed or deleted by a PCP server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcpMapping {
+    /// Identifies the mapping.
+    pub nonce: Nonce,
+    /// The protocol of the mapping.
+    pub protocol: Protocol,
+    /// The internal port of the mapping.
+    pub internal_port: u16,
+    /// The external address and port the server assigned.
+    pub external: SocketAddr,
+    /// The lifetime the server granted, zero if the mapping was deleted.
+    pub lifetime: Duration
+}
+
+/// A client of a PCP server, usually the default gateway.
+///
+/// Unlike NAT-PMP, PCP supports IPv6 and gateways of carrier-grade NATs.
+/// Requests are retransmitted as RFC 6887 specifies: after about 3s at first,
+/// doubling the interval up to about 1024s, each randomized by ±10%.
+#[derive(Clone, Debug)]
+pub struct Pcp {
+    server: SocketAddr,
+    client: IpAddr,
+    tries: Option<u32>,
+    /// The nonces of mappings created through `PortMapper`, shared by clones.
+    pub(crate) nonces: Arc<Mutex<HashMap<(Protocol, u16), Nonce>>>
+}
+
+impl Pcp {
+    /// Create a client of the PCP server with the given address.
+    ///
+    /// Requests are sent from the local address used to reach the server.
+    pub fn new(server: IpAddr) -> Result<Self> {
+        let server = SocketAddr::new(server, PORT);
+        let client = util::probe_local_addr(server)?;
+        trace!("pcp client address for {}: {}", server, client);
+        Ok(Pcp { server, client, tries: None, nonces: Arc::default() })
+    }
+
+    /// Create a client of a server listening on a port other than 5351.
+    #[cfg(test)]
+    pub(crate) fn with_server(server: SocketAddr, client: IpAddr, tries: u32) -> Self {
+        Pcp { server, client, tries: Some(tries), nonces: Arc::default() }
+    }
+
+    /// Create a client of the default gateway.
+    ///
+    /// Looking up the default gateway is only supported on Linux.
+    pub fn default_gateway() -> Result<Self> {
+        Pcp::new(IpAddr::V4(util::default_gateway()?))
+    }
+
+    /// The address of the server.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// The address requests are sent from.
+    pub fn client(&self) -> IpAddr {
+        self.client
+    }
+
+    /// Set how often a request is sent before giving up.
+    ///
+    /// By default, requests are retransmitted until the server responds, as
+    /// RFC 6887 specifies. Requests are always sent at least once.
+    pub fn set_tries(&mut self, tries: u32) {
+        self.tries = Some(tries)
+    }
+
+    /// Check that the server supports PCP with an ANNOUNCE request.
+    pub(crate) fn announce(&self) -> impl Future<Item=(), Error=Error> {
+        let bytes = self.header(OP_ANNOUNCE, Duration::from_secs(0));
+        let retransmission = self.retransmission();
+        util::udp_request(self.server, self.client, bytes, retransmission, |response| {
+            parse_announce(response).transpose()
+        })
+    }
+
+    /// Create, refresh or delete a mapping with the MAP opcode.
+    pub fn map(&self, request: &MapRequest) -> impl Future<Item=PcpMapping, Error=Error> {
+        let bytes = self.encode_map(request);
+        self.request(OP_MAP, bytes, request.nonce, request.protocol, request.internal_port)
+    }
+
+    /// Create or refresh a mapping with the PEER opcode.
+    pub fn peer(&self, request: &PeerRequest) -> impl Future<Item=PcpMapping, Error=Error> {
+        let bytes = self.encode_peer(request);
+        self.request(OP_PEER, bytes, request.nonce, request.protocol, request.internal_port)
+    }
+
+    fn encode_map(&self, request: &MapRequest) -> Vec<u8> {
+        let mut bytes = self.header(OP_MAP, request.lifetime);
+        bytes.extend_from_slice(&self.map_payload(
+            &request.nonce,
+            request.protocol,
+            request.internal_port,
+            request.external_port,
+            request.external_ip
+        ));
+        if let Some(addr) = request.third_party {
+            push_option(&mut bytes, OPTION_THIRD_PARTY, &ip_bytes(addr))
+        }
+        if request.prefer_failure {
+            push_option(&mut bytes, OPTION_PREFER_FAILURE, &[])
+        }
+        for filter in &request.filters {
+            let prefix_length = match filter.remote_ip {
+                IpAddr::V4(_) => filter.prefix_length.saturating_add(96),
+                IpAddr::V6(_) => filter.prefix_length
+            };
+            let mut data = vec![0, prefix_length];
+            data.extend_from_slice(&filter.remote_port.unwrap_or(0).to_be_bytes());
+            data.extend_from_slice(&ip_bytes(filter.remote_ip));
+            push_option(&mut bytes, OPTION_FILTER, &data)
+        }
+        bytes
+    }
+
+    fn encode_peer(&self, request: &PeerRequest) -> Vec<u8> {
+        let mut bytes = self.header(OP_PEER, request.lifetime);
+        bytes.extend_from_slice(&self.map_payload(
+            &request.nonce,
+            request.protocol,
+            request.internal_port,
+            request.external_port,
+            request.external_ip
+        ));
+        bytes.extend_from_slice(&request.remote.port().to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&ip_bytes(request.remote.ip()));
+        if let Some(addr) = request.third_party {
+            push_option(&mut bytes, OPTION_THIRD_PARTY, &ip_bytes(addr))
+        }
+        bytes
+    }
+
+    /// The common request header.
+    fn header(&self, opcode: u8, lifetime: Duration) -> Vec<u8> {
+        let lifetime = std::cmp::min(lifetime.as_secs(), u64::from(u32::MAX)) as u32;
+        let mut bytes = vec![VERSION, opcode, 0, 0];
+        bytes.extend_from_slice(&lifetime.to_be_bytes());
+        bytes.extend_from_slice(&ip_bytes(self.client));
+        bytes
+    }
+
+    /// The MAP payload, which also starts the PEER payload.
+    fn map_payload(
+        &self,
+        nonce: &Nonce,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: Option<u16>,
+        external_ip: Option<IpAddr>
+    ) -> Vec<u8> {
+        let unspecified = match self.client {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let external_ip = external_ip.unwrap_or(unspecified);
+        let mut bytes = nonce.0.to_vec();
+        bytes.extend_from_slice(&[protocol.number(), 0, 0, 0]);
+        bytes.extend_from_slice(&internal_port.to_be_bytes());
+        bytes.extend_from_slice(&external_port.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&ip_bytes(external_ip));
+        bytes
+    }
+
+    fn request(&self, opcode: u8, bytes: Vec<u8>, nonce: Nonce, proto: Protocol, port: u16)
+        -> impl Future<Item=PcpMapping, Error=Error>
+    {
+        let retransmission = self.retransmission();
+        util::udp_request(self.server, self.client, bytes, retransmission, move |response| {
+            parse_response(response, opcode, &nonce, proto, port).transpose()
+        })
+        .map(|mapping| {
+            trace!("{} port {} maps to {} for {:?}",
+                mapping.protocol, mapping.internal_port, mapping.external, mapping.lifetime);
+            mapping
+        })
+    }
+}
+
+impl Pcp {
+    /// The retransmission schedule of RFC 6887.
+    fn retransmission(&self) -> util::Retransmission {
+        util::Retransmission {
+            interval: randomize(IRT),
+            backoff: |interval| randomize(std::cmp::min(interval * 2, MRT)),
+            tries: self.tries.map(|tries| std::cmp::max(tries, 1))
+        }
+    }
+}
+
+/// Multiply `duration` by a random factor between 0.9 and 1.1.
+fn randomize(duration: Duration) -> Duration {
+    duration.mul_f64(rand::thread_rng().gen_range(0.9, 1.1))
+}
+
+/// An address as encoded in PCP messages, IPv4 addresses being IPv4-mapped.
+fn ip_bytes(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets()
+    }
+}
+
+fn parse_ip(bytes: &[u8]) -> IpAddr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&bytes[.. 16]);
+    let addr = Ipv6Addr::from(octets);
+    match addr.to_ipv4_mapped() {
+        Some(addr) => IpAddr::V4(addr),
+        None => IpAddr::V6(addr)
+    }
+}
+
+/// Append an option, padding its data to a multiple of 4 bytes.
+fn push_option(bytes: &mut Vec<u8>, code: u8, data: &[u8]) {
+    bytes.extend_from_slice(&[code, 0]);
+    bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(data);
+    bytes.resize(bytes.len() + (4 - data.len() % 4) % 4, 0)
+}
+
+/// Check the version and opcode of a response, returning its result code.
+fn parse_header(bytes: &[u8], opcode: u8) -> Result<Option<u8>> {
+    if bytes.len() < 4 || bytes[1] != 0x80 | opcode {
+        return Ok(None)
+    }
+    if bytes[0] != VERSION {
+        // A NAT-PMP gateway, which responds with its own version.
+        return Err(Error::Pcp(PcpCode::UnsupportedVersion))
+    }
+    if bytes.len() < HEADER_SIZE {
+        return Ok(None)
+    }
+    Ok(Some(bytes[3]))
+}
+
+/// Parse a response to an ANNOUNCE request.
+fn parse_announce(bytes: &[u8]) -> Result<Option<()>> {
+    match parse_header(bytes, OP_ANNOUNCE)? {
+        Some(0) => Ok(Some(())),
+        Some(code) => Err(Error::Pcp(PcpCode::from(code))),
+        None => Ok(None)
+    }
+}
+
+/// Parse a response to a MAP or PEER request.
+///
+/// Returns `None` if the datagram is not a response to the request with the
+/// given opcode, nonce, protocol and internal port.
+fn parse_response(bytes: &[u8], opcode: u8, nonce: &Nonce, proto: Protocol, port: u16)
+    -> Result<Option<PcpMapping>>
+{
+    let code = match parse_header(bytes, opcode)? {
+        Some(code) if bytes.len() >= HEADER_SIZE + MAP_SIZE => code,
+        _ => return Ok(None)
+    };
+    let payload = &bytes[HEADER_SIZE ..];
+    let matches = payload[.. 12] == nonce.0
+        && payload[12] == proto.number()
+        && payload[16 .. 18] == port.to_be_bytes();
+    if !matches {
+        return Ok(None)
+    }
+    if code != 0 {
+        return Err(Error::Pcp(PcpCode::from(code)))
+    }
+    let lifetime = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let external_port = u16::from_be_bytes([payload[18], payload[19]]);
+    Ok(Some(PcpMapping {
+        nonce: *nonce,
+        protocol: proto,
+        internal_port: port,
+        external: SocketAddr::new(parse_ip(&payload[20 ..]), external_port),
+        lifetime: Duration::from_secs(u64::from(lifetime))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn client() -> Pcp {
+        Pcp {
+            server: "192.168.1.1:5351".parse().unwrap(),
+            client: "192.168.1.10".parse().unwrap(),
+            tries: None,
+            nonces: Arc::default()
+        }
+    }
+
+    /// A MAP response to `request` with the given result code.
+    fn response(request: &[u8], result: u8) -> Vec<u8> {
+        let mut response = vec![VERSION, 0x80 | request[1], 0, result, 0, 0, 0x1c, 0x20];
+        response.extend_from_slice(&[0; 16]);
+        response.extend_from_slice(&request[HEADER_SIZE .. HEADER_SIZE + 18]);
+        response.extend_from_slice(&[0x76, 0x60, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        response.extend_from_slice(&[203, 0, 113, 1]);
+        response
+    }
+
+    #[test]
+    fn map_request() {
+        let pcp = client();
+        let mut request = MapRequest::new(Protocol::Udp, 30303, Duration::from_secs(7200));
+        request.prefer_failure = true;
+        request.filters.push(Filter {
+            remote_ip: "198.51.100.0".parse().unwrap(),
+            prefix_length: 24,
+            remote_port: None
+        });
+        let bytes = pcp.encode_map(&request);
+        assert_eq!(HEADER_SIZE + MAP_SIZE + 4 + 24, bytes.len());
+        assert_eq!(&[2, 1, 0, 0, 0, 0, 0x1c, 0x20][..], &bytes[.. 8]);
+        assert_eq!(&ip_bytes("192.168.1.10".parse().unwrap())[..], &bytes[8 .. 24]);
+        assert_eq!(&request.nonce.0[..], &bytes[24 .. 36]);
+        assert_eq!(&[17, 0, 0, 0, 0x76, 0x5f, 0x76, 0x5f][..], &bytes[36 .. 44]);
+        assert_eq!(&ip_bytes(IpAddr::V4(Ipv4Addr::UNSPECIFIED))[..], &bytes[44 .. 60]);
+        assert_eq!(&[2, 0, 0, 0, 3, 0, 0, 20, 0, 120, 0, 0][..], &bytes[60 .. 72]);
+        assert_eq!(&ip_bytes("198.51.100.0".parse().unwrap())[..], &bytes[72 ..])
+    }
+
+    #[test]
+    fn parse_responses() {
+        let pcp = client();
+        let nonce = Nonce::random();
+        let mut request = MapRequest::new(Protocol::Tcp, 30303, Duration::from_secs(7200));
+        request.nonce = nonce;
+        let request = pcp.encode_map(&request);
+        let mapping = parse_response(&response(&request, 0), OP_MAP, &nonce, Protocol::Tcp, 30303)
+            .unwrap()
+            .unwrap();
+        assert_eq!("203.0.113.1:30304".parse::<SocketAddr>().unwrap(), mapping.external);
+        assert_eq!(Duration::from_secs(7200), mapping.lifetime);
+
+        let other = Nonce::random();
+        let parsed = parse_response(&response(&request, 0), OP_MAP, &other, Protocol::Tcp, 30303);
+        assert_eq!(None, parsed.unwrap());
+        match parse_response(&response(&request, 8), OP_MAP, &nonce, Protocol::Tcp, 30303) {
+            Err(Error::Pcp(PcpCode::NoResources)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        match parse_response(&[0, 0x81, 0, 1, 0, 0, 0, 0], OP_MAP, &nonce, Protocol::Tcp, 30303) {
+            Err(Error::Pcp(PcpCode::UnsupportedVersion)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn port_mapper_reuses_nonce() {
+        use crate::PortMapper;
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let client = "127.0.0.1".parse().unwrap();
+        let pcp = Pcp::with_server(server, client, 1);
+        let handle = thread::spawn(move || {
+            let mut nonces = Vec::new();
+            for _ in 0 .. 3 {
+                let mut buffer = [0; 1100];
+                let (n, from) = socket.recv_from(&mut buffer).unwrap();
+                socket.send_to(&response(&buffer[.. n], 0), from).unwrap();
+                nonces.push(buffer[HEADER_SIZE .. HEADER_SIZE + 12].to_vec())
+            }
+            nonces
+        });
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let lifetime = Duration::from_secs(7200);
+        let mapping = rt.block_on(PortMapper::map(&pcp, Protocol::Udp, 30303, lifetime)).unwrap();
+        assert_eq!(30304, mapping.external_port);
+        rt.block_on(PortMapper::map(&pcp, Protocol::Udp, 30303, lifetime)).unwrap();
+        rt.block_on(pcp.unmap(&mapping)).unwrap();
+        assert!(pcp.nonces.lock().unwrap().is_empty());
+        let nonces = handle.join().unwrap();
+        assert!(nonces.iter().all(|nonce| *nonce == nonces[0]))
+    }
+
+    #[test]
+    fn peer() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let client = "127.0.0.1".parse().unwrap();
+        let pcp = Pcp::with_server(server, client, 1);
+        let handle = thread::spawn(move || {
+            let mut buffer = [0; 1100];
+            let (n, from) = socket.recv_from(&mut buffer).unwrap();
+            socket.send_to(&response(&buffer[.. n], 0), from).unwrap();
+            buffer[.. n].to_vec()
+        });
+        let remote = "198.51.100.1:443".parse().unwrap();
+        let request = PeerRequest::new(Protocol::Tcp, 30303, remote, Duration::from_secs(60));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mapping = rt.block_on(pcp.peer(&request)).unwrap();
+        assert_eq!(request.nonce, mapping.nonce);
+        let bytes = handle.join().unwrap();
+        assert_eq!(HEADER_SIZE + MAP_SIZE + 20, bytes.len());
+        assert_eq!(&[0x01, 0xbb, 0, 0][..], &bytes[60 .. 64]);
+        assert_eq!(&ip_bytes(remote.ip())[..], &bytes[64 .. 80])
+    }
+}
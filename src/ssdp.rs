@@ -0,0 +1,429 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{error::{Error, Result}, http, util::{self, SEARCH_TARGETS}};
+use futures::{prelude::*, try_ready};
+use log::{debug, trace};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::{Duration, Instant}
+};
+use tokio_timer::Delay;
+use tokio_udp::UdpSocket;
+use unicase::Ascii;
+use url::{Host, Url};
+
+/// How often the M-SEARCH request is sent by default.
+const TRIES: u32 = 3;
+
+/// How long to wait for responses after each M-SEARCH request by default.
+const WAIT: Duration = Duration::from_secs(1);
+
+/// The IPv4 SSDP multicast address.
+pub(crate) const MULTICAST_V4: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+
+/// The link-local and site-local IPv6 SSDP multicast addresses, sent to
+/// from the interface with the given index.
+pub(crate) fn multicast_v6(interface: u32) -> Vec<SocketAddr> {
+    let link_local = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc);
+    let site_local = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xc);
+    vec![
+        SocketAddr::V6(SocketAddrV6::new(link_local, 1900, 0, interface)),
+        SocketAddr::V6(SocketAddrV6::new(site_local, 1900, 0, interface))
+    ]
+}
+
+/// Parameters of an M-SEARCH.
+#[derive(Clone, Debug)]
+pub(crate) struct SearchConfig {
+    /// How often the M-SEARCH requests are sent.
+    pub(crate) tries: u32,
+    /// How long to wait for responses after each round of requests.
+    pub(crate) wait: Duration,
+    /// The `MX` header value, i.e. the maximum response delay in seconds.
+    pub(crate) mx: u8,
+    /// The multicast addresses requests are sent to.
+    pub(crate) addrs: Vec<SocketAddr>,
+    /// The search targets, most preferred first.
+    pub(crate) targets: Vec<String>,
+    /// The `CPFN.UPNP.ORG` header value.
+    pub(crate) friendly_name: String,
+    /// Drop responses whose location fails `is_valid_location`.
+    pub(crate) validate_location: bool
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            tries: TRIES,
+            wait: WAIT,
+            mx: 1,
+            addrs: vec![MULTICAST_V4],
+            targets: SEARCH_TARGETS.iter().map(|st| st.to_string()).collect(),
+            friendly_name: "upnp-igdp-crate".to_string(),
+            validate_location: true
+        }
+    }
+}
+
+/// A response to an SSDP M-SEARCH request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchResponse {
+    /// The URL of the device description (`LOCATION`).
+    pub location: Url,
+    /// The unique service name (`USN`).
+    pub usn: Option<String>,
+    /// The search target (`ST`).
+    pub st: Option<String>,
+    /// The `SERVER` header.
+    pub server: Option<Server>,
+    /// How long the response is valid (`CACHE-CONTROL: max-age`).
+    pub max_age: Option<Duration>,
+    /// Increased whenever the device reboots or changes its network (`BOOTID.UPNP.ORG`).
+    pub boot_id: Option<u32>,
+    /// Increased whenever the device description changes (`CONFIGID.UPNP.ORG`).
+    pub config_id: Option<u32>,
+    /// The port the device answers unicast M-SEARCH requests on (`SEARCHPORT.UPNP.ORG`).
+    pub search_port: Option<u16>
+}
+
+/// The tokens of a `SERVER` header, e.g. `Linux/3.14 UPnP/1.1 MiniUPnPd/2.1`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Server {
+    /// The operating system and its version, e.g. `Linux/3.14`.
+    pub os: String,
+    /// The UPnP version, e.g. `UPnP/1.1`.
+    pub upnp: String,
+    /// The product and its version, e.g. `MiniUPnPd/2.1`.
+    pub product: String
+}
+
+impl Server {
+    /// Split a `SERVER` header value at its UPnP version token.
+    ///
+    /// If there is no such token, the whole value is taken as product.
+    pub fn parse(value: &str) -> Self {
+        let separator = |c: char| c == ',' || c.is_whitespace();
+        let trim = |s: &str| s.trim_matches(separator).to_string();
+        let is_upnp = |t: &str| t.get(.. 5).map(|p| Ascii::new(p) == "UPnP/").unwrap_or(false);
+        // Every separator, and the end of the value, ends a token.
+        let mut start = 0;
+        let ends = value.char_indices().filter(|(_, c)| separator(*c));
+        for (end, c) in ends.chain(std::iter::once((value.len(), ' '))) {
+            if is_upnp(&value[start .. end]) {
+                return Server {
+                    os: trim(&value[.. start]),
+                    upnp: value[start .. end].to_string(),
+                    product: trim(&value[end ..])
+                }
+            }
+            start = end + c.len_utf8()
+        }
+        Server { os: String::new(), upnp: String::new(), product: trim(value) }
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens = [&self.os, &self.upnp, &self.product];
+        let mut tokens = tokens.iter().filter(|t| !t.is_empty());
+        if let Some(t) = tokens.next() {
+            f.write_str(t)?
+        }
+        for t in tokens {
+            write!(f, " {}", t)?
+        }
+        Ok(())
+    }
+}
+
+impl SearchResponse {
+    /// The key used to detect duplicate responses.
+    pub(crate) fn key(&self) -> String {
+        self.usn.clone().unwrap_or_else(|| self.location.to_string())
+    }
+
+    /// The position of this response's search target in `targets`.
+    ///
+    /// Lower is better, unknown search targets rank last.
+    pub(crate) fn rank(&self, targets: &[String]) -> usize {
+        self.st.as_ref()
+            .and_then(|st| targets.iter().position(|t| Ascii::new(t) == Ascii::new(st)))
+            .unwrap_or(targets.len())
+    }
+}
+
+/// Parse a single M-SEARCH response datagram.
+pub(crate) fn parse_response(bytes: &[u8]) -> Result<SearchResponse> {
+    let (head, _) = http::parse_head(bytes)?.ok_or(Error::Incomplete)?;
+    if Some(200) != head.code {
+        debug!("m-search response code = {:?}", head.code);
+        return Err(Error::StatusCode(head.code))
+    }
+    search_response(&head, "ST")
+}
+
+/// Extract the SSDP headers of a response or announcement.
+///
+/// The search target is taken from the header named `target`, which is
+/// `ST` in M-SEARCH responses and `NT` in `NOTIFY` announcements.
+pub(crate) fn search_response(head: &http::Head, target: &str) -> Result<SearchResponse> {
+    let location = head.header("LOCATION").and_then(|loc| Url::parse(loc).ok());
+    let location = location.ok_or(Error::Location)?;
+    let number = |name| head.header(name).and_then(|n| n.parse().ok());
+    Ok(SearchResponse {
+        location,
+        usn: head.header("USN").map(String::from),
+        st: head.header(target).map(String::from),
+        server: head.header("SERVER").map(Server::parse),
+        max_age: head.header("CACHE-CONTROL").and_then(max_age),
+        boot_id: number("BOOTID.UPNP.ORG"),
+        config_id: number("CONFIGID.UPNP.ORG"),
+        search_port: number("SEARCHPORT.UPNP.ORG").and_then(|p: u32| u16::try_from(p).ok())
+    })
+}
+
+/// Check that `location` points to the sender of the response, `from`, and
+/// that this is a private or link-local address.
+///
+/// Otherwise any host could direct our requests anywhere, including to hosts
+/// outside of the local network.
+pub(crate) fn is_valid_location(location: &Url, from: &SocketAddr) -> bool {
+    let host = match location.host() {
+        Some(Host::Ipv4(addr)) => util::unmapped(IpAddr::V4(addr)),
+        Some(Host::Ipv6(addr)) => util::unmapped(IpAddr::V6(addr)),
+        Some(Host::Domain(_)) | None => return false
+    };
+    if host != util::unmapped(from.ip()) {
+        return false
+    }
+    match host {
+        IpAddr::V4(addr) => addr.is_private() || addr.is_link_local(),
+        IpAddr::V6(addr) => util::is_unicast_link_local(&addr) || util::is_unique_local(&addr)
+    }
+}
+
+/// The `max-age` directive of a `CACHE-CONTROL` header value.
+fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',')
+        .filter_map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next()?.trim();
+            let value = parts.next()?.trim().trim_matches('"');
+            if Ascii::new(name) == "max-age" { value.parse().ok() } else { None }
+        })
+        .next()
+        .map(Duration::from_secs)
+}
+
+/// A stream of distinct M-SEARCH responses.
+///
+/// An M-SEARCH request for every search target and multicast address is sent
+/// up to `tries` times, waiting `wait` for responses after each round. Since we use UDP, frames
+/// may get lost, so retrying seems advisable. Responses are
+/// deduplicated by their USN and invalid responses are skipped.
+#[derive(Debug)]
+pub(crate) struct Search {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+    config: SearchConfig,
+    /// The destination, search target and bytes of every request.
+    requests: Vec<(SocketAddr, usize, Vec<u8>)>,
+    /// Index of the next request to send in the current round.
+    next: usize,
+    tries: u32,
+    delay: Delay,
+    seen: HashSet<String>,
+    /// Stop at the end of the round in which the first response arrived.
+    first_round: bool
+}
+
+impl Search {
+    pub(crate) fn new(socket: UdpSocket, buffer: Vec<u8>, config: SearchConfig) -> Self {
+        let mut requests = Vec::new();
+        for addr in &config.addrs {
+            for (i, st) in config.targets.iter().enumerate() {
+                let name = &config.friendly_name;
+                requests.push((*addr, i, util::format_search_request(addr, config.mx, st, name)))
+            }
+        }
+        Search {
+            socket,
+            buffer,
+            config,
+            requests,
+            next: 0,
+            tries: 0,
+            delay: Delay::new(Instant::now()),
+            seen: HashSet::new(),
+            first_round: false
+        }
+    }
+
+    /// End the stream at the end of the first round that produced a response.
+    pub(crate) fn first_round(mut self) -> Self {
+        self.first_round = true;
+        self
+    }
+}
+
+impl Stream for Search {
+    type Item = (SocketAddr, SearchResponse);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            while self.next < self.requests.len() {
+                let (addr, target, ref request) = self.requests[self.next];
+                try_ready!(self.socket.poll_send_to(request, &addr));
+                trace!("sent m-search request for {} to {}", self.config.targets[target], addr);
+                self.next += 1;
+                if self.next == self.requests.len() {
+                    self.tries += 1;
+                    self.delay.reset(Instant::now() + self.config.wait)
+                }
+            }
+            if let Async::Ready((n, from)) = self.socket.poll_recv_from(&mut self.buffer)? {
+                trace!("received m-search response from {}", from);
+                match parse_response(&self.buffer[.. n]) {
+                    Ok(ref response)
+                        if self.config.validate_location
+                            && !is_valid_location(&response.location, &from) =>
+                    {
+                        debug!("ignoring m-search response from {} with location {}",
+                            from, response.location)
+                    }
+                    Ok(response) => {
+                        if self.seen.insert(response.key()) {
+                            return Ok(Async::Ready(Some((from, response))))
+                        }
+                        trace!("ignoring duplicate response from {}", from)
+                    }
+                    Err(e) => debug!("ignoring invalid m-search response from {}: {}", from, e)
+                }
+                continue
+            }
+            try_ready!(self.delay.poll().map_err(|_| Error::Timer));
+            if self.tries >= self.config.tries || (self.first_round && !self.seen.is_empty()) {
+                return Ok(Async::Ready(None))
+            }
+            self.next = 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_search_response() {
+        let bytes = b"HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age=120\r\n\
+            ST: urn:schemas-upnp-org:service:WANIPConnection:2\r\n\
+            USN: uuid:ebf5a0a0-1dd1-11b2-a90f-e0469a4b5b46::urn:schemas-upnp-org:service:WANIPConnection:2\r\n\
+            EXT:\r\n\
+            SERVER: Linux/3.14 UPnP/1.1 MiniUPnPd/2.1\r\n\
+            LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\
+            BOOTID.UPNP.ORG: 1\r\n\
+            CONFIGID.UPNP.ORG: 1337\r\n\
+            SEARCHPORT.UPNP.ORG: 1901\r\n\r\n";
+        let response = parse_response(&bytes[..]).unwrap();
+        assert_eq!("http://192.168.1.1:5000/rootDesc.xml", response.location.as_str());
+        assert_eq!(Some(Duration::from_secs(120)), response.max_age);
+        assert_eq!(Some(Server {
+            os: "Linux/3.14".to_string(),
+            upnp: "UPnP/1.1".to_string(),
+            product: "MiniUPnPd/2.1".to_string()
+        }), response.server);
+        assert_eq!((Some(1), Some(1337), Some(1901)),
+            (response.boot_id, response.config_id, response.search_port));
+        let usn = "uuid:ebf5a0a0-1dd1-11b2-a90f-e0469a4b5b46::\
+            urn:schemas-upnp-org:service:WANIPConnection:2";
+        assert_eq!(Some(usn), response.usn.as_deref());
+        assert_eq!(0, response.rank(&SearchConfig::default().targets))
+    }
+
+    #[test]
+    fn rank_search_targets() {
+        let targets = SearchConfig::default().targets;
+        let rank = |st: &str| {
+            let location = Url::parse("http://192.168.1.1/").unwrap();
+            SearchResponse {
+                location,
+                usn: None,
+                st: Some(st.to_string()),
+                server: None,
+                max_age: None,
+                boot_id: None,
+                config_id: None,
+                search_port: None
+            }.rank(&targets)
+        };
+        assert!(rank(util::WAN_IP_CONNECTION_1) < rank(util::WAN_PPP_CONNECTION_1));
+        assert!(rank(util::WAN_PPP_CONNECTION_1) < rank(util::IGD_1));
+        assert!(rank(util::IGD_1) < rank("upnp:rootdevice"))
+    }
+
+    #[test]
+    fn validate_location() {
+        let valid = |location: &str, from: &str| {
+            is_valid_location(&Url::parse(location).unwrap(), &from.parse().unwrap())
+        };
+        assert!(valid("http://192.168.1.1:5000/rootDesc.xml", "192.168.1.1:1900"));
+        assert!(valid("http://10.0.0.1/desc.xml", "10.0.0.1:1900"));
+        assert!(valid("http://169.254.0.1/desc.xml", "169.254.0.1:1900"));
+        assert!(valid("http://[fe80::1]:5000/desc.xml", "[fe80::1%2]:1900"));
+        assert!(valid("http://[fd00::1]:5000/desc.xml", "[fd00::1]:1900"));
+        assert!(valid("http://192.168.1.1:5000/rootDesc.xml", "[::ffff:192.168.1.1]:1900"));
+        assert!(!valid("http://8.8.8.8/desc.xml", "[::ffff:8.8.8.8]:1900"));
+        assert!(!valid("http://192.168.1.2:5000/rootDesc.xml", "192.168.1.1:1900"));
+        assert!(!valid("http://8.8.8.8/desc.xml", "8.8.8.8:1900"));
+        assert!(!valid("http://[2001:db8::1]/desc.xml", "[2001:db8::1]:1900"));
+        assert!(!valid("http://router.local/desc.xml", "192.168.1.1:1900"))
+    }
+
+    #[test]
+    fn parse_server() {
+        let server = Server::parse("Linux/2.6.36, UPnP/1.0, Portable SDK for UPnP devices/1.6.6");
+        assert_eq!("Linux/2.6.36", server.os);
+        assert_eq!("UPnP/1.0", server.upnp);
+        assert_eq!("Portable SDK for UPnP devices/1.6.6", server.product);
+        let server = Server::parse("FRITZ!Box 7590 UPnP/1.0 AVM FRITZ!Box 7590 154.07.29");
+        assert_eq!("FRITZ!Box 7590", server.os);
+        assert_eq!("AVM FRITZ!Box 7590 154.07.29", server.product);
+        let server = Server::parse("Linux/5.4,\u{a0}UPnP/1.1,\u{a0}Product/2");
+        assert_eq!(("Linux/5.4", "UPnP/1.1", "Product/2"),
+            (&server.os[..], &server.upnp[..], &server.product[..]));
+        let server = Server::parse("Custom/1.0");
+        assert_eq!(("", "", "Custom/1.0"), (&server.os[..], &server.upnp[..], &server.product[..]));
+        assert_eq!("Custom/1.0", server.to_string())
+    }
+
+    #[test]
+    fn parse_max_age() {
+        assert_eq!(Some(Duration::from_secs(1800)), max_age("max-age=1800"));
+        assert_eq!(Some(Duration::from_secs(60)), max_age("no-cache=\"Ext\", MAX-AGE = 60"));
+        assert_eq!(None, max_age("no-cache"))
+    }
+
+    #[test]
+    fn parse_search_response_without_location() {
+        let bytes = b"HTTP/1.1 200 OK\r\nUSN: uuid:1234\r\n\r\n";
+        match parse_response(&bytes[..]) {
+            Err(Error::Location) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+}
@@ -8,107 +8,658 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use bytes::Bytes;
-use crate::error::{Error, Result};
-use futures::prelude::*;
-use log::trace;
-use std::{net::{IpAddr, SocketAddr}, time::Duration};
-use tokio_codec::{FramedRead, FramedWrite, BytesCodec};
+use crate::{Config, Control, Protocol, error::{Error, Result, Step}, http, xml};
+use futures::{future::{self, Either}, prelude::*, sync::oneshot, try_ready};
+use log::{debug, trace};
+use std::{
+    fs,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant}
+};
 use tokio_tcp::TcpStream;
-use url::{Host, Url};
-
-pub(crate) const SSDP_SEARCH_REQUEST: &[u8] =
-    b"M-SEARCH * HTTP/1.1\r\n\
-    Host: 239.255.255.250:1900\r\n\
-    MAN: \"ssdp:discover\"\r\n\
-    MX: 1\r\n\
-    ST: urn:schemas-upnp-org:service:WANIPConnection:2\r\n\
-    CPFN.UPNP.ORG: upnp-igdp-crate\r\n\r\n";
-
-pub(crate) const SERVICE_TYPE: &str =
-    "urn:schemas-upnp-org:service:WANIPConnection:2";
-
-pub(crate) const GET_EXTERNAL_IP_SOAP_ENV: &str =
-    r#"<?xml version="1.0" encoding="utf-8"?>
-    <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-        <s:Body>
-            <u:GetExternalIPAddress xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:2"/>
-        </s:Body>
-    </s:Envelope>
-    "#;
-
-pub(crate) fn url2sock(url: &Url) -> Result<SocketAddr> {
-    match (url.host(), url.port()) {
-        (Some(Host::Ipv4(addr)), Some(port)) => Ok(SocketAddr::new(IpAddr::V4(addr), port)),
-        (Some(Host::Ipv6(addr)), Some(port)) => Ok(SocketAddr::new(IpAddr::V6(addr), port)),
-        _                                    => Err(Error::HostPort)
-    }
-}
-
-pub(crate) fn fetch(addr: SocketAddr, req: String) -> impl Future<Item=Bytes, Error=Error> {
-    TcpStream::connect(&addr)
-        .from_err()
+use tokio_timer::{Delay, Timeout};
+use tokio_udp::UdpSocket;
+use url::{Host, Position, Url};
+
+pub(crate) const WAN_IP_CONNECTION_2: &str = "urn:schemas-upnp-org:service:WANIPConnection:2";
+pub(crate) const WAN_IP_CONNECTION_1: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+pub(crate) const WAN_PPP_CONNECTION_1: &str = "urn:schemas-upnp-org:service:WANPPPConnection:1";
+pub(crate) const WAN_IPV6_FIREWALL_CONTROL_1: &str =
+    "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+pub(crate) const IGD_2: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:2";
+pub(crate) const IGD_1: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Services which provide port mappings, most preferred first.
+pub(crate) const CONNECTION_SERVICES: &[&str] = &[
+    WAN_IP_CONNECTION_2,
+    WAN_IP_CONNECTION_1,
+    WAN_PPP_CONNECTION_1
+];
+
+/// SSDP search targets, most preferred first.
+pub(crate) const SEARCH_TARGETS: &[&str] = &[
+    WAN_IP_CONNECTION_2,
+    WAN_IP_CONNECTION_1,
+    WAN_PPP_CONNECTION_1,
+    IGD_2,
+    IGD_1
+];
+
+pub(crate) fn format_search_request(addr: &SocketAddr, mx: u8, st: &str, name: &str) -> Vec<u8> {
+    let addr = SocketAddr::new(addr.ip(), addr.port()); // without IPv6 scope ID
+    format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         Host: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: {}\r\n\
+         ST: {}\r\n\
+         CPFN.UPNP.ORG: {}\r\n\r\n", addr, mx, st, name).into_bytes()
+}
+
+/// Parse a URL whose IPv6 host may carry a zone ID, e.g. `http://[fe80::1%eth0]:5000/`.
+///
+/// Returns the URL without the zone ID and the interface index of the zone,
+/// or 0 if there is none. Zones are given as index or, on Linux, as name.
+pub(crate) fn parse_url(s: &str) -> Result<(Url, u32)> {
+    // Only look for a zone ID in the authority, not in the path or query.
+    let start = s.find("://").map_or(0, |i| i + 3);
+    let end = s[start ..].find(&['/', '?', '#'][..]).map_or(s.len(), |i| start + i);
+    let authority = &s[.. end]; // including the scheme
+    if let (Some(start), Some(end)) = (authority.find('['), authority.find(']')) {
+        if let Some(percent) = s.get(start .. end).and_then(|host| host.find('%')) {
+            let percent = start + percent;
+            let mut zone = &s[percent + 1 .. end];
+            if zone.len() > 2 && zone.starts_with("25") {
+                zone = &zone[2 ..] // "%25" is the URL encoding of "%"
+            }
+            let scope_id = interface_index(zone)
+                .ok_or(Error::Url(url::ParseError::InvalidIpv6Address))?;
+            let url = Url::parse(&format!("{}{}", &s[.. percent], &s[end ..]))?;
+            return Ok((url, scope_id))
+        }
+    }
+    Ok((Url::parse(s)?, 0))
+}
+
+/// The index of the network interface with the given index or name.
+fn interface_index(zone: &str) -> Option<u32> {
+    if let Ok(index) = zone.parse() {
+        return Some(index)
+    }
+    if zone.is_empty() || zone.contains('/') || zone.starts_with('.') {
+        return None
+    }
+    let index = fs::read_to_string(format!("/sys/class/net/{}/ifindex", zone)).ok()?;
+    index.trim().parse().ok()
+}
+
+/// Resolve the host and port of an `http` URL, which defaults to 80.
+///
+/// `fetch` only speaks plain HTTP, so other schemes fail with `Error::Scheme`.
+/// Link-local IPv6 addresses get the given `scope_id` unless it is 0.
+///
+/// Host names are looked up with the blocking system resolver, which is done
+/// on a new thread for every lookup. Gateways are looked up rarely, so this
+/// is cheaper than keeping a resolver thread around.
+pub(crate) fn resolve(url: &Url, scope_id: u32) -> impl Future<Item=SocketAddr, Error=Error> {
+    if url.scheme() != "http" {
+        return Either::A(future::err(Error::Scheme(url.scheme().to_string())))
+    }
+    let port = match url.port_or_known_default() {
+        Some(port) => port,
+        None => return Either::A(future::err(Error::HostPort))
+    };
+    match url.host() {
+        Some(Host::Ipv4(addr)) => Either::A(future::ok(SocketAddr::new(IpAddr::V4(addr), port))),
+        Some(Host::Ipv6(addr)) => {
+            let scope_id = if is_unicast_link_local(&addr) { scope_id } else { 0 };
+            Either::A(future::ok(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope_id))))
+        }
+        Some(Host::Domain(host)) => Either::B(lookup(host.to_string(), port)),
+        None => Either::A(future::err(Error::HostPort))
+    }
+}
+
+fn lookup(host: String, port: u16) -> impl Future<Item=SocketAddr, Error=Error> {
+    let (tx, rx) = oneshot::channel();
+    let spawned = thread::Builder::new()
+        .name("upnp-igdp-resolver".to_string())
+        .spawn(move || {
+            trace!("resolving {}", host);
+            let _ = tx.send((host.as_str(), port).to_socket_addrs().map(|mut a| a.next()));
+        });
+    if let Err(e) = spawned {
+        return Either::A(future::err(e.into()))
+    }
+    Either::B(rx.then(|result| {
+        match result {
+            Ok(Ok(Some(addr))) => Ok(addr),
+            Ok(Err(e)) => Err(e.into()),
+            Ok(Ok(None)) | Err(oneshot::Canceled) => Err(Error::HostPort)
+        }
+    }))
+}
+
+/// Find the IPv4 address of the default gateway.
+///
+/// Only supported on Linux, where the routing table is read from `/proc/net/route`.
+pub(crate) fn default_gateway() -> io::Result<Ipv4Addr> {
+    if !cfg!(target_os = "linux") {
+        let e = io::Error::new(io::ErrorKind::Unsupported, "default gateway lookup not supported");
+        return Err(e)
+    }
+    parse_default_route(&fs::read_to_string("/proc/net/route")?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no default gateway"))
+}
+
+/// Find the gateway of the default route in the contents of `/proc/net/route`.
+///
+/// Addresses are printed as hexadecimal numbers in host byte order.
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+    const RTF_UP: u16 = 0x1;
+    const RTF_GATEWAY: u16 = 0x2;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (destination, gateway, flags) = (fields.get(1)?, fields.get(2)?, fields.get(3)?);
+        let flags = u16::from_str_radix(flags, 16).ok()?;
+        if *destination != "00000000" || flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+            return None
+        }
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// Whether `addr` is in fe80::/10.
+pub(crate) fn is_unicast_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Whether `addr` is in fc00::/7.
+pub(crate) fn is_unique_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xfe00 == 0xfc00
+}
+
+/// Turn an IPv4-mapped IPv6 address, e.g. the sender seen by a dual-stack
+/// socket, into the IPv4 address it maps.
+pub(crate) fn unmapped(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        if let [0, 0, 0, 0, 0, 0xffff, ..] = v6.segments() {
+            let [_, _, _, _, _, _, _, _, _, _, _, _, a, b, c, d] = v6.octets();
+            return IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+        }
+    }
+    addr
+}
+
+/// Check that a control URL resolved to `addr` points to the gateway, whose
+/// description we fetched from `gateway`.
+///
+/// Otherwise a gateway could direct our SOAP requests to any host, just like
+/// an M-SEARCH response whose location fails `ssdp::is_valid_location`.
+pub(crate) fn check_control_addr(url: &Url, addr: SocketAddr, gateway: SocketAddr) -> Result<()> {
+    if unmapped(addr.ip()) != unmapped(gateway.ip()) {
+        debug!("control url {} does not point to the gateway at {}", url, gateway.ip());
+        return Err(Error::ControlUrl)
+    }
+    Ok(())
+}
+
+/// The host and port of `url`, as used in the `Host` header.
+pub(crate) fn host_header(url: &Url) -> &str {
+    &url[Position::BeforeHost .. Position::AfterPort]
+}
+
+/// Find the local address the operating system uses to reach `gateway`.
+///
+/// Connecting a UDP socket does not send anything, but picks the route.
+pub(crate) fn probe_local_addr(gateway: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified = match gateway {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    socket.connect(gateway)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+pub(crate) fn fetch(addr: SocketAddr, req: String, config: &Config)
+    -> impl Future<Item=http::Response, Error=Error>
+{
+    let config = config.clone();
+    timeout(TcpStream::connect(&addr), config.connect_timeout, Step::Connect)
         .and_then(move |conn| {
             trace!("sending request to {}", addr);
-            let codec = FramedWrite::new(conn, BytesCodec::new());
-            codec.send(req.into_bytes().into()).from_err().map(|codec| codec.into_inner())
+            let send = tokio_io::io::write_all(conn, req.into_bytes());
+            timeout(send, config.send_timeout, Step::Send).map(move |(conn, _)| (conn, config))
         })
-        .and_then(move |conn| {
+        .and_then(move |(conn, config)| {
             trace!("reading response from {}", addr);
-            let codec = FramedRead::new(conn, BytesCodec::new());
-            codec.concat2().from_err().map(|b| b.freeze()) // TODO: Timeout
+            let recv = http::read_response(conn, config.max_response_size);
+            timeout(recv, config.receive_timeout, Step::Receive)
         })
 }
 
-pub(crate) fn format_get_req(host: &SocketAddr, path: &str) -> String {
-    format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host)
+/// When to retransmit a UDP request.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Retransmission {
+    /// How long to wait for a response to the first request.
+    pub(crate) interval: Duration,
+    /// Computes the next interval from the previous one.
+    pub(crate) backoff: fn(Duration) -> Duration,
+    /// How often the request is sent, without limit if `None`.
+    pub(crate) tries: Option<u32>
 }
 
-pub(crate) fn format_external_ip(host: &SocketAddr, path: &str) -> String {
-    format!(
-        "POST {} HTTP/1.1\r\n\
-         Host: {}\r\n\
-         Content-Length: {}\r\n\
-         Content-Type: text/xml\r\n\
-         SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:2#GetExternalIPAddress\"\r\n\
-         Connection: Close\r\n\r\n\
-         {}
-        ", path, host, GET_EXTERNAL_IP_SOAP_ENV.len(), GET_EXTERNAL_IP_SOAP_ENV)
+/// Send a UDP request from an ephemeral port of `bind` to `server`,
+/// retransmitting it until `parse` accepts a response.
+///
+/// Datagrams from other addresses and those `parse` returns `None` for are
+/// ignored. Fails with `Error::Timeout(Step::Receive)` once all tries are used up.
+pub(crate) fn udp_request<F, T>(
+    server: SocketAddr,
+    bind: IpAddr,
+    request: Vec<u8>,
+    retransmission: Retransmission,
+    parse: F
+) -> impl Future<Item=T, Error=Error>
+where
+    F: Fn(&[u8]) -> Option<Result<T>>
+{
+    let socket = match UdpSocket::bind(&SocketAddr::new(bind, 0)) {
+        Ok(socket) => socket,
+        Err(e) => return Either::A(future::err(e.into()))
+    };
+    Either::B(UdpRequest {
+        socket,
+        server,
+        request,
+        buffer: vec![0; UDP_BUFFER_SIZE],
+        interval: retransmission.interval,
+        retransmission,
+        delay: Delay::new(Instant::now()),
+        send: true,
+        parse
+    })
 }
 
-pub(crate) struct PortMapping<'a> {
-    pub(crate) protocol: super::Protocol,
-    pub(crate) address: IpAddr,
-    pub(crate) port: u16,
-    pub(crate) description: &'a str,
-    pub(crate) duration: Duration
+/// The size of the buffer UDP responses are received into, the maximum size
+/// of PCP messages.
+const UDP_BUFFER_SIZE: usize = 1100;
+
+struct UdpRequest<F> {
+    socket: UdpSocket,
+    server: SocketAddr,
+    request: Vec<u8>,
+    buffer: Vec<u8>,
+    retransmission: Retransmission,
+    /// How long to wait for a response to the last request sent.
+    interval: Duration,
+    delay: Delay,
+    /// Whether the request is due to be sent.
+    send: bool,
+    parse: F
+}
+
+impl<F, T> Future for UdpRequest<F>
+where
+    F: Fn(&[u8]) -> Option<Result<T>>
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if self.send {
+                try_ready!(self.socket.poll_send_to(&self.request, &self.server));
+                trace!("sent request to {}", self.server);
+                self.send = false;
+                if let Some(tries) = self.retransmission.tries.as_mut() {
+                    *tries = tries.saturating_sub(1)
+                }
+                self.delay.reset(Instant::now() + self.interval)
+            }
+            while let Async::Ready((n, from)) = self.socket.poll_recv_from(&mut self.buffer)? {
+                if from != self.server {
+                    debug!("ignoring datagram from {}", from);
+                    continue
+                }
+                match (self.parse)(&self.buffer[.. n]) {
+                    Some(result) => return result.map(Async::Ready),
+                    None => debug!("ignoring unexpected response from {}", from)
+                }
+            }
+            try_ready!(self.delay.poll().map_err(|_| Error::Timer));
+            if self.retransmission.tries == Some(0) {
+                debug!("request to {} timed out", self.server);
+                return Err(Error::Timeout(Step::Receive))
+            }
+            self.interval = (self.retransmission.backoff)(self.interval);
+            self.send = true
+        }
+    }
+}
+
+/// Fail with `Error::Timeout(step)` unless `future` completes within `duration`.
+pub(crate) fn timeout<F>(future: F, duration: Duration, step: Step)
+    -> impl Future<Item=F::Item, Error=Error>
+where
+    F: Future,
+    F::Error: Into<Error>
+{
+    Timeout::new(future, duration).map_err(move |e| {
+        if e.is_elapsed() {
+            debug!("{} timed out after {:?}", step, duration);
+            Error::Timeout(step)
+        } else if e.is_inner() {
+            e.into_inner().expect("is_inner() is true").into()
+        } else {
+            Error::Timer
+        }
+    })
 }
 
-pub(crate) fn format_add_any_port_mapping(host: &SocketAddr, path: &str, pm: &PortMapping) -> String {
+/// The path and query of `url`, as used in the request line.
+pub(crate) fn request_target(url: &Url) -> &str {
+    &url[Position::BeforePath .. Position::AfterQuery]
+}
+
+pub(crate) fn format_get_req(url: &Url) -> String {
+    format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        request_target(url), host_header(url))
+}
+
+/// The version number of a UPnP service type, e.g. 2 for
+/// `urn:schemas-upnp-org:service:WANIPConnection:2`.
+pub(crate) fn service_version(service: &str) -> u32 {
+    service.rsplit(':').next().and_then(|v| v.parse().ok()).unwrap_or(1)
+}
+
+pub(crate) fn format_soap_request(
+    url: &Url,
+    service: &str,
+    action: &str,
+    args: &[(&str, String)]
+) -> String {
+    let mut arguments = String::new();
+    for (name, value) in args {
+        if value.is_empty() {
+            arguments.push_str(&format!("<u:{}/>", name))
+        } else {
+            arguments.push_str(&format!("<u:{}>{}</u:{}>", name, xml::escape(value), name))
+        }
+    }
     let body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
         <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
             <s:Body>
-                <u:AddAnyPortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:2">
-                    <u:NewRemoteHost/>
-                    <u:NewExternalPort>0</u:NewExternalPort>
-                    <u:NewProtocol>{}</u:NewProtocol>
-                    <u:NewInternalPort>{}</u:NewInternalPort>
-                    <u:NewInternalClient>{}</u:NewInternalClient>
-                    <u:NewEnabled>true</u:NewEnabled>
-                    <u:NewPortMappingDescription>{}</u:NewPortMappingDescription>
-                    <u:NewLeaseDuration>{}</u:NewLeaseDuration>
-                </u:AddAnyPortMapping>
+                <u:{} xmlns:u="{}">{}</u:{}>
             </s:Body>
         </s:Envelope>
-        "#, pm.protocol, pm.port, pm.address, pm.description, pm.duration.as_secs());
+        "#, action, service, arguments, action);
 
     format!(
         "POST {} HTTP/1.1\r\n\
          Host: {}\r\n\
          Content-Length: {}\r\n\
          Content-Type: text/xml\r\n\
-         SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:2#AddAnyPortMapping\"\r\n\
+         SOAPAction: \"{}#{}\"\r\n\
          Connection: Close\r\n\r\n\
-         {}
-        ", path, host, body.len(), body)
+         {}",
+        request_target(url), host_header(url), body.len(), service, action, body)
+}
+
+pub(crate) fn format_external_ip(ctrl: &Control) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "GetExternalIPAddress", &[])
+}
+
+pub(crate) struct PortMapping<'a> {
+    pub(crate) protocol: Protocol,
+    pub(crate) address: IpAddr,
+    pub(crate) port: u16,
+    pub(crate) description: &'a str,
+    pub(crate) duration: Duration
+}
+
+impl<'a> PortMapping<'a> {
+    fn arguments(&self, external_port: u16) -> Vec<(&'static str, String)> {
+        vec![
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", self.protocol.to_string()),
+            ("NewInternalPort", self.port.to_string()),
+            ("NewInternalClient", self.address.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", self.description.to_string()),
+            ("NewLeaseDuration", self.duration.as_secs().to_string())
+        ]
+    }
+}
+
+/// `AddAnyPortMapping` lets the gateway choose the external port (IGD v2 only).
+pub(crate) fn format_add_any_port_mapping(ctrl: &Control, pm: &PortMapping) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "AddAnyPortMapping", &pm.arguments(0))
+}
+
+/// `AddPortMapping` maps the given external port.
+pub(crate) fn format_add_port_mapping(ctrl: &Control, pm: &PortMapping, external: u16) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "AddPortMapping", &pm.arguments(external))
+}
+
+pub(crate) fn format_delete_port_mapping(
+    ctrl: &Control,
+    p: Protocol,
+    port: u16,
+    remote: Option<IpAddr>
+) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "DeletePortMapping", &[
+        ("NewRemoteHost", remote.map(|a| a.to_string()).unwrap_or_default()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", p.to_string())
+    ])
+}
+
+pub(crate) fn format_delete_port_mapping_range(
+    ctrl: &Control,
+    p: Protocol,
+    start: u16,
+    end: u16,
+    manage: bool
+) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "DeletePortMappingRange", &[
+        ("NewStartPort", start.to_string()),
+        ("NewEndPort", end.to_string()),
+        ("NewProtocol", p.to_string()),
+        ("NewManage", if manage { "1" } else { "0" }.to_string())
+    ])
+}
+
+pub(crate) fn format_get_specific_port_mapping_entry(
+    ctrl: &Control,
+    p: Protocol,
+    port: u16,
+    remote: Option<IpAddr>
+) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "GetSpecificPortMappingEntry", &[
+        ("NewRemoteHost", remote.map(|a| a.to_string()).unwrap_or_default()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", p.to_string())
+    ])
+}
+
+pub(crate) fn format_get_generic_port_mapping_entry(ctrl: &Control, index: u16) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "GetGenericPortMappingEntry", &[
+        ("NewPortMappingIndex", index.to_string())
+    ])
+}
+
+pub(crate) fn format_get_list_of_port_mappings(ctrl: &Control, p: Protocol) -> String {
+    format_soap_request(&ctrl.url, &ctrl.service, "GetListOfPortMappings", &[
+        ("NewStartPort", "0".to_string()),
+        ("NewEndPort", "65535".to_string()),
+        ("NewProtocol", p.to_string()),
+        ("NewManage", "1".to_string()),
+        ("NewNumberOfPorts", "0".to_string())
+    ])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::Igdp;
+    use std::{io::{Read, Write}, thread};
+
+    /// Answer one HTTP request on a local port with `body`.
+    pub(crate) fn soap_server(body: &str) -> (SocketAddr, thread::JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let body = body.to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 4096];
+            let _ = stream.read(&mut buffer).unwrap();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(body.as_bytes()).unwrap()
+        });
+        (addr, handle)
+    }
+
+    /// An instance controlling the `WANIPConnection:2` service at `addr`.
+    pub(crate) fn igdp_at(addr: SocketAddr) -> Igdp<Control> {
+        Igdp {
+            local: "192.168.1.10".parse().unwrap(),
+            config: Config::default(),
+            bound: None,
+            state: Control {
+                url: format!("http://{}/ctl/IPConn", addr).parse().unwrap(),
+                addr,
+                service: WAN_IP_CONNECTION_2.to_string(),
+                verify: false
+            }
+        }
+    }
+
+    fn control(service: &str) -> Control {
+        Control {
+            url: "http://192.168.1.1:5000/ctl/IPConn".parse().unwrap(),
+            addr: "192.168.1.1:5000".parse().unwrap(),
+            service: service.to_string(),
+            verify: false
+        }
+    }
+
+    #[test]
+    fn probe_loopback() {
+        let addr = probe_local_addr("127.0.0.1:1900".parse().unwrap()).unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), addr)
+    }
+
+    #[test]
+    fn receive_timeout() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config { receive_timeout: Duration::from_millis(100), .. Config::default() };
+        let req = format_get_req(&format!("http://{}/", addr).parse().unwrap());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        match rt.block_on(fetch(addr, req, &config)) {
+            Err(Error::Timeout(Step::Receive)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn parse_zone_ids() {
+        let (url, scope_id) = parse_url("http://[fe80::1%3]:5000/desc.xml").unwrap();
+        assert_eq!(("http://[fe80::1]:5000/desc.xml", 3), (url.as_str(), scope_id));
+        let (url, scope_id) = parse_url("http://[fe80::1%25lo]/desc.xml").unwrap();
+        assert_eq!("http://[fe80::1]/desc.xml", url.as_str());
+        if cfg!(target_os = "linux") {
+            assert_eq!(1, scope_id)
+        }
+        assert_eq!(0, parse_url("http://[fe80::1]:5000/").unwrap().1);
+        let (url, scope_id) = parse_url("http://192.168.1.1/desc.xml?q=[a%2]").unwrap();
+        assert_eq!(("http://192.168.1.1/desc.xml?q=[a%2]", 0), (url.as_str(), scope_id));
+        assert!(parse_url("http://[fe80::1%no/such/interface]/").is_err())
+    }
+
+    #[test]
+    fn resolve_default_ports() {
+        let resolve = |url: &str, scope_id| resolve(&url.parse().unwrap(), scope_id).wait();
+        let addr = resolve("http://192.168.1.1/desc.xml", 0).unwrap();
+        assert_eq!("192.168.1.1:80".parse::<SocketAddr>().unwrap(), addr);
+        match resolve("https://192.168.1.1/desc.xml", 0) {
+            Err(Error::Scheme(scheme)) => assert_eq!("https", scheme),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let addr = resolve("http://localhost:5000/desc.xml", 0).unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(5000, addr.port());
+        match resolve("http://[fe80::1]:5000/", 3).unwrap() {
+            SocketAddr::V6(addr) => assert_eq!(3, addr.scope_id()),
+            other => panic!("unexpected address: {}", other)
+        }
+        match resolve("http://[2001:db8::1]:5000/", 3).unwrap() {
+            SocketAddr::V6(addr) => assert_eq!(0, addr.scope_id()),
+            other => panic!("unexpected address: {}", other)
+        }
+    }
+
+    #[test]
+    fn control_addr_on_gateway() {
+        let url = "http://192.168.1.1:5000/ctl".parse().unwrap();
+        let gateway = "192.168.1.1:5000".parse().unwrap();
+        let check = |addr: &str| check_control_addr(&url, addr.parse().unwrap(), gateway);
+        assert!(check("192.168.1.1:5001").is_ok());
+        assert!(check("[::ffff:192.168.1.1]:80").is_ok());
+        match check("203.0.113.1:80") {
+            Err(Error::ControlUrl) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn search_request_host() {
+        let addr = "[ff02::c%2]:1900".parse().unwrap();
+        let req = String::from_utf8(format_search_request(&addr, 2, IGD_1, "test")).unwrap();
+        assert!(req.contains("\r\nHost: [ff02::c]:1900\r\n"));
+        assert!(req.contains("\r\nMX: 2\r\n"));
+        assert!(req.contains("\r\nCPFN.UPNP.ORG: test\r\n"))
+    }
+
+    #[test]
+    fn default_route() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            eth0\t0010A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\n";
+        if cfg!(target_endian = "little") {
+            assert_eq!(Some(Ipv4Addr::new(192, 168, 1, 1)), parse_default_route(routes))
+        }
+        assert_eq!(None, parse_default_route(&routes[.. routes.rfind("eth0").unwrap()]))
+    }
+
+    #[test]
+    fn service_versions() {
+        assert_eq!(1, service_version(WAN_IP_CONNECTION_1));
+        assert_eq!(2, service_version(WAN_IP_CONNECTION_2));
+        assert_eq!(1, service_version("urn:schemas-upnp-org:service:WANIPConnection"))
+    }
+
+    #[test]
+    fn soap_request_uses_service_type() {
+        let pm = PortMapping {
+            protocol: Protocol::Udp,
+            address: "192.168.1.10".parse().unwrap(),
+            port: 30303,
+            description: "a <b> & c",
+            duration: Duration::from_secs(60)
+        };
+        let req = format_add_port_mapping(&control(WAN_IP_CONNECTION_1), &pm, pm.port);
+        assert!(req.starts_with("POST /ctl/IPConn HTTP/1.1\r\n"));
+        assert!(req.contains("SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\""));
+        assert!(req.contains(r#"<u:AddPortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">"#));
+        assert!(req.contains("<u:NewExternalPort>30303</u:NewExternalPort>"));
+        assert!(req.contains("<u:NewPortMappingDescription>a &lt;b&gt; &amp; c</u:NewPortMappingDescription>"))
+    }
 }
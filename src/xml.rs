@@ -27,9 +27,31 @@ impl<'a, 'd> Cursor<'a, 'd> {
         }
     }
 
+    /// All child elements with the given name.
+    pub(crate) fn children<'n>(&'n self, name: &'n str) -> impl Iterator<Item=Cursor<'a, 'd>> + 'n {
+        self.node.iter()
+            .flat_map(|n| n.children())
+            .filter(move |n| n.has_tag_name(name))
+            .map(Cursor::new)
+    }
+
     pub(crate) fn text(&self) -> Option<&str> {
         self.node.as_ref().and_then(|n| n.text())
     }
 }
 
-
+/// Escape the XML special characters in `s`.
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '&'  => escaped.push_str("&amp;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c    => escaped.push(c)
+        }
+    }
+    escaped
+}